@@ -26,12 +26,14 @@ mkdir -p $RPM_BUILD_ROOT/usr/sbin $RPM_BUILD_ROOT/etc/piragua $RPM_BUILD_ROOT/li
 cp $RPM_BUILD_DIR/target/release/piragua $RPM_BUILD_ROOT/usr/sbin/piragua
 cp $RPM_BUILD_DIR/systemd/piragua.service $RPM_BUILD_ROOT/lib/systemd/system
 cp $RPM_BUILD_DIR/systemd/environment $RPM_BUILD_ROOT/etc/piragua/
+cp $RPM_BUILD_DIR/systemd/piragua.toml $RPM_BUILD_ROOT/etc/piragua/
 
 %files
 /usr/sbin/piragua
 /lib/systemd/system/piragua.service
 %dir /etc/piragua
 %config(noreplace) /etc/piragua/environment
+%config(noreplace) /etc/piragua/piragua.toml
 
 %doc
 
@@ -0,0 +1,321 @@
+//! POSIX ACLs on volume directories so several users or groups can share a
+//! volume.
+//!
+//! ACLs are set with the `system.posix_acl_access` and
+//! `system.posix_acl_default` xattrs through gfapi.  gfapi-sys hands xattrs
+//! back as UTF-8 strings, which mangles the binary ACL, so the entries we set
+//! are also kept as JSON in `user.piragua.acl` and reported from there.
+//! Clients have to mount with the `acl` option for them to be enforced.
+//!
+//! Once a directory has an access ACL the group bits of its mode are the
+//! ACL's mask, not the owning group's permissions.  The mode it had before is
+//! kept in `user.piragua.mode` so later ACLs get the group entry right and
+//! removing the ACL puts the mode back.
+use std::path::Path;
+
+use gfapi_sys::gluster::Gluster;
+use libc::mode_t;
+
+use crate::error::{ApiError, ApiResult};
+
+const ACCESS_XATTR: &str = "system.posix_acl_access";
+const DEFAULT_XATTR: &str = "system.posix_acl_default";
+const ACL_XATTR: &str = "user.piragua.acl";
+const MODE_XATTR: &str = "user.piragua.mode";
+const MAX_ENTRIES: usize = 32;
+
+// From linux/posix_acl_xattr.h
+const ACL_VERSION: u32 = 2;
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+const ACL_UNDEFINED_ID: u32 = u32::max_value();
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum AclType {
+    #[serde(rename = "user")]
+    User,
+    #[serde(rename = "group")]
+    Group,
+}
+
+/// Access for a named user or group, ie `{"type": "group", "id": 2001,
+/// "perms": "rwx"}`
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AclEntry {
+    #[serde(rename = "type")]
+    pub acl_type: AclType,
+    pub id: u32,
+    /// Any of r, w and x, with - for the ones left out
+    pub perms: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct Acl {
+    /// Access to the volume directory
+    pub access: Vec<AclEntry>,
+    /// Access inherited by everything created in it
+    pub default: Vec<AclEntry>,
+}
+
+impl Acl {
+    pub fn is_empty(&self) -> bool { self.access.is_empty() && self.default.is_empty() }
+}
+
+fn parse_perms(perms: &str) -> ApiResult<u16> {
+    let mut bits = 0;
+    for c in perms.chars() {
+        bits |= match c {
+            'r' => 4,
+            'w' => 2,
+            'x' => 1,
+            '-' => 0,
+            _ => {
+                let msg = format!("ACL perms {:?} may only have r, w, x and -", perms);
+                return Err(ApiError::InvalidInput(msg));
+            }
+        };
+    }
+    Ok(bits)
+}
+
+/// Check the entries before anything is set
+pub fn validate(acl: &Acl) -> ApiResult<()> {
+    for entries in &[&acl.access, &acl.default] {
+        if entries.len() > MAX_ENTRIES {
+            let msg = format!("An ACL can have at most {} entries", MAX_ENTRIES);
+            return Err(ApiError::InvalidInput(msg));
+        }
+        for (i, entry) in entries.iter().enumerate() {
+            parse_perms(&entry.perms)?;
+            if entry.id == ACL_UNDEFINED_ID {
+                return Err(ApiError::InvalidInput(format!("ACL id {} is not valid", entry.id)));
+            }
+            if entries[..i].iter().any(|e| e.acl_type == entry.acl_type && e.id == entry.id) {
+                let msg =
+                    format!("ACL has more than one entry for {:?} {}", entry.acl_type, entry.id);
+                return Err(ApiError::InvalidInput(msg));
+            }
+        }
+    }
+    Ok(())
+}
+
+// The xattr value: a version then tag, perm and id for each entry, little
+// endian and sorted by tag then id.  The owner, group and other entries come
+// from the mode and the mask lets every entry through, like setfacl does.
+fn encode(entries: &[AclEntry], mode: mode_t) -> ApiResult<Vec<u8>> {
+    let group_perms = ((mode >> 3) & 7) as u16;
+    let mut named = vec![];
+    let mut mask = group_perms;
+    for entry in entries {
+        let tag = match entry.acl_type {
+            AclType::User => ACL_USER,
+            AclType::Group => ACL_GROUP,
+        };
+        let perms = parse_perms(&entry.perms)?;
+        mask |= perms;
+        named.push((tag, perms, entry.id));
+    }
+    let mut all = vec![(ACL_USER_OBJ, ((mode >> 6) & 7) as u16, ACL_UNDEFINED_ID),
+                       (ACL_GROUP_OBJ, group_perms, ACL_UNDEFINED_ID),
+                       (ACL_OTHER, (mode & 7) as u16, ACL_UNDEFINED_ID)];
+    if !named.is_empty() {
+        all.append(&mut named);
+        all.push((ACL_MASK, mask, ACL_UNDEFINED_ID));
+    }
+    all.sort_by_key(|&(tag, _, id)| (tag, id));
+
+    let mut value = ACL_VERSION.to_le_bytes().to_vec();
+    for (tag, perms, id) in all {
+        value.extend_from_slice(&tag.to_le_bytes());
+        value.extend_from_slice(&perms.to_le_bytes());
+        value.extend_from_slice(&id.to_le_bytes());
+    }
+    Ok(value)
+}
+
+// Set or clear one xattr
+fn set_xattr(gluster: &Gluster, path: &Path, name: &str, value: Option<&[u8]>) -> ApiResult<()> {
+    let result = match value {
+        Some(value) => gluster.setxattr(path, name, value, 0),
+        None => gluster.removexattr(path, name),
+    };
+    match result.map_err(ApiError::from) {
+        // Removing one that isn't there
+        Err(ApiError::NotFound(_)) if value.is_none() => Ok(()),
+        result => result,
+    }
+}
+
+// What setting an access ACL changes on a directory whose mode is `mode` and
+// that had `recorded` before any ACL: the xattr, the mode to record first and
+// the mode to put back after
+#[derive(Debug, PartialEq)]
+struct AccessChange {
+    access: Option<Vec<u8>>,
+    record: Option<mode_t>,
+    restore: Option<mode_t>,
+}
+
+fn access_change(entries: &[AclEntry],
+                 mode: mode_t,
+                 recorded: Option<mode_t>)
+                 -> ApiResult<AccessChange> {
+    if entries.is_empty() {
+        return Ok(AccessChange { access: None, record: None, restore: recorded });
+    }
+    let record = if recorded.is_none() { Some(mode) } else { None };
+    let access = encode(entries, recorded.unwrap_or(mode))?;
+    Ok(AccessChange { access: Some(access), record, restore: None })
+}
+
+// The mode a directory had before its access ACL, if it has one
+fn recorded_mode(gluster: &Gluster, path: &Path) -> ApiResult<Option<mode_t>> {
+    match gluster.getxattr(path, MODE_XATTR) {
+        Ok(data) => match mode_t::from_str_radix(data.trim_end_matches('\0'), 8) {
+            Ok(mode) => Ok(Some(mode)),
+            Err(_) => Err(ApiError::Internal(format!("{} of {} is {:?}, not an octal mode",
+                                                     MODE_XATTR,
+                                                     path.display(),
+                                                     data))),
+        },
+        Err(e) => match ApiError::from(e) {
+            ApiError::NotFound(_) => Ok(None),
+            e => Err(e),
+        },
+    }
+}
+
+// Replace a directory's access ACL.  Returns the mode its owner, group and
+// other entries come from
+fn set_access(gluster: &Gluster,
+              path: &Path,
+              entries: &[AclEntry],
+              mode: mode_t)
+              -> ApiResult<mode_t> {
+    let recorded = recorded_mode(gluster, path)?;
+    let change = access_change(entries, mode, recorded)?;
+    // Before the ACL overwrites the group bits
+    if let Some(mode) = change.record {
+        gluster.setxattr(path, MODE_XATTR, format!("{:o}", mode).as_bytes(), 0)?;
+    }
+    set_xattr(gluster, path, ACCESS_XATTR, change.access.as_ref().map(|v| v.as_slice()))?;
+    if let Some(mode) = change.restore {
+        gluster.chmod(path, mode)?;
+        set_xattr(gluster, path, MODE_XATTR, None)?;
+    }
+    Ok(recorded.unwrap_or(mode))
+}
+
+/// Set a directory's ACL, replacing the one it had.  `mode` is the
+/// directory's mode, which the owner, group and other entries have to agree
+/// with.
+pub fn apply(gluster: &Gluster, path: &Path, acl: &Acl, mode: mode_t) -> ApiResult<()> {
+    let mode = set_access(gluster, path, &acl.access, mode)?;
+    let default = if acl.default.is_empty() { None } else { Some(encode(&acl.default, mode)?) };
+    set_xattr(gluster, path, DEFAULT_XATTR, default.as_ref().map(|v| v.as_slice()))?;
+    let json = if acl.is_empty() { None } else { Some(serde_json::to_string(acl)?) };
+    set_xattr(gluster, path, ACL_XATTR, json.as_ref().map(|j| j.as_bytes()))
+}
+
+/// Let everyone named in the volume directory's ACL through its parent, the
+/// volume's top directory, without being able to list or change it
+pub fn apply_traverse(gluster: &Gluster, path: &Path, acl: &Acl, mode: mode_t) -> ApiResult<()> {
+    let entries: Vec<AclEntry> = acl.access
+                                    .iter()
+                                    .chain(acl.default.iter())
+                                    .map(|e| AclEntry { perms: "--x".into(), ..e.clone() })
+                                    .fold(vec![], |mut entries, e| {
+                                        if !entries.contains(&e) {
+                                            entries.push(e);
+                                        }
+                                        entries
+                                    });
+    set_access(gluster, path, &entries, mode).map(|_| ())
+}
+
+/// The ACL piragua set on a directory
+pub fn read(gluster: &Gluster, path: &Path) -> ApiResult<Acl> {
+    match gluster.getxattr(path, ACL_XATTR) {
+        Ok(data) => Ok(serde_json::from_str(data.trim_end_matches('\0'))?),
+        Err(e) => match ApiError::from(e) {
+            ApiError::NotFound(_) => Ok(Acl::default()),
+            e => Err(e),
+        },
+    }
+}
+
+#[test]
+fn test_acl() {
+    let entry = |acl_type, id, perms: &str| AclEntry { acl_type, id, perms: perms.into() };
+
+    // Just the mode
+    assert_eq!(encode(&[], 0o750).unwrap(),
+               vec![2, 0, 0, 0, 1, 0, 7, 0, 255, 255, 255, 255, 4, 0, 5, 0, 255, 255, 255, 255,
+                    32, 0, 0, 0, 255, 255, 255, 255]);
+    // Named entries are sorted and widen the mask
+    let value = encode(&[entry(AclType::Group, 2001, "rwx"), entry(AclType::User, 1000, "r-x")],
+                       0o750).unwrap();
+    let tags: Vec<(u16, u16)> =
+        value[4..].chunks(8)
+                  .map(|e| (u16::from_le_bytes([e[0], e[1]]), u16::from_le_bytes([e[2], e[3]])))
+                  .collect();
+    assert_eq!(tags,
+               vec![(ACL_USER_OBJ, 7),
+                    (ACL_USER, 5),
+                    (ACL_GROUP_OBJ, 5),
+                    (ACL_GROUP, 7),
+                    (ACL_MASK, 7),
+                    (ACL_OTHER, 0)]);
+    assert_eq!(&value[16..20], &1000u32.to_le_bytes());
+
+    let acl = |entries| Acl { access: entries, default: vec![] };
+    assert!(validate(&acl(vec![entry(AclType::Group, 2001, "rw-")])).is_ok());
+    assert!(validate(&acl(vec![entry(AclType::Group, 2001, "rwz")])).is_err());
+    assert!(validate(&acl(vec![entry(AclType::User, 1, "r"), entry(AclType::User, 1, "w")]))
+                .is_err());
+    assert!(validate(&acl(vec![entry(AclType::User, 1, "r"), entry(AclType::Group, 1, "w")]))
+                .is_ok());
+}
+
+#[test]
+fn test_acl_keeps_mode() {
+    let entry = |perms: &str| AclEntry { acl_type: AclType::Group, id: 2001, perms: perms.into() };
+    let perms = |value: &[u8], tag: u16| {
+        value[4..].chunks(8)
+                  .find(|e| u16::from_le_bytes([e[0], e[1]]) == tag)
+                  .map(|e| u16::from_le_bytes([e[2], e[3]]))
+    };
+    // What gluster does to the mode and the recorded mode
+    let set = |entries: &[AclEntry], mode: mode_t, recorded: Option<mode_t>| {
+        let change = access_change(entries, mode, recorded).unwrap();
+        let recorded = change.record.or(recorded);
+        match (&change.access, change.restore) {
+            (Some(access), _) => {
+                assert_eq!(perms(access, ACL_GROUP_OBJ), Some(5));
+                let mask = perms(access, ACL_MASK).unwrap() as mode_t;
+                ((mode & !0o70) | mask << 3, recorded)
+            }
+            (None, Some(mode)) => (mode, None),
+            (None, None) => (mode, recorded),
+        }
+    };
+
+    let (mode, recorded) = set(&[entry("rwx")], 0o2750, None);
+    assert_eq!((mode, recorded), (0o2770, Some(0o2750)));
+    // Widen then narrow: the group entry stays r-x though the mode says rwx
+    let (mode, recorded) =
+        set(&[entry("rwx"), AclEntry { id: 1000, ..entry("rw-") }], mode, recorded);
+    assert_eq!((mode, recorded), (0o2770, Some(0o2750)));
+    let (mode, recorded) = set(&[entry("r--")], mode, recorded);
+    assert_eq!((mode, recorded), (0o2750, Some(0o2750)));
+    let (mode, recorded) = set(&[], mode, recorded);
+    assert_eq!((mode, recorded), (0o2750, None));
+    // Nothing to put back on a directory that never had one
+    assert_eq!(set(&[], 0o750, None), (0o750, None));
+}
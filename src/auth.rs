@@ -0,0 +1,684 @@
+//! JWT authentication.
+//!
+//! Tokens are checked against a set of keys loaded from the config.  A token
+//! that names a key with the `kid` header is only checked against that key,
+//! otherwise every active key is tried in the order they're configured.
+//!
+//! HS256 keys are shared secrets.  RS256 and ES256 keys are public keys read
+//! from PEM files or a JWKS document so only the token issuer holds the
+//! signing key.
+use std::{fmt, fs, path::PathBuf, str::FromStr, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{decode, decode_header, errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use rocket::{http::Status,
+             request::{self, FromRequest},
+             Outcome, Request, State};
+
+use crate::config::{AuthConfig, KeyConfig, LiveConfig};
+
+/// The key id given to `auth.secret` / `auth.secret_file`
+pub const DEFAULT_KEY_ID: &str = "default";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub iss: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub qsh: String,
+}
+
+// Json Web Token
+pub struct Jwt {
+    pub claims: Claims,
+    /// Id of the key that validated this token
+    pub key_id: String,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Key {
+    pub id: String,
+    /// The only algorithm tokens checked against this key may use
+    pub algorithm: Algorithm,
+    /// Tokens signed with this key are rejected after this time
+    pub expires: Option<DateTime<Utc>>,
+    decoding: DecodingKey<'static>,
+    // The secret or public key as configured.  Used to spot changes on reload
+    material: Vec<u8>,
+}
+
+// Never print the secret
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key")
+         .field("id", &self.id)
+         .field("algorithm", &self.algorithm)
+         .field("expires", &self.expires)
+         .finish()
+    }
+}
+
+impl Key {
+    pub fn is_active(&self, now: &DateTime<Utc>) -> bool {
+        match self.expires {
+            Some(ref expires) => expires > now,
+            None => true,
+        }
+    }
+
+    /// The shared secret tokens can be signed with.  None for public keys
+    pub fn secret(&self) -> Option<&[u8]> {
+        if is_hmac(self.algorithm) {
+            Some(&self.material)
+        } else {
+            None
+        }
+    }
+}
+
+/// Every key a token may be signed with
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeySet {
+    keys: Vec<Key>,
+    /// Seconds a token is still accepted after it expires, from auth.leeway
+    pub leeway: u64,
+}
+
+/// A JSON Web Key Set as described in RFC 7517.  Only the fields needed to
+/// verify RSA and EC signatures are read.
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    key_use: Option<String>,
+    // RSA
+    n: Option<String>,
+    e: Option<String>,
+    // EC
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+// Decode a base64 secret given either inline or in a file
+fn read_secret(name: &str,
+               secret: &Option<String>,
+               secret_file: &Option<PathBuf>)
+               -> Result<Vec<u8>, String> {
+    let encoded = match (secret, secret_file) {
+        (Some(_), Some(_)) => {
+            return Err(format!("only one of {0}.secret and {0}.secret_file may be set", name));
+        }
+        (Some(s), None) => s.clone(),
+        (None, Some(p)) => {
+            fs::read_to_string(p).map_err(|e| {
+                                     format!("{}.secret_file {}: {}", name, p.display(), e)
+                                 })?
+        }
+        (None, None) => return Err(format!("{0}.secret or {0}.secret_file must be set", name)),
+    };
+    base64::decode(encoded.trim()).map_err(|e| format!("{} is not valid base64: {}", name, e))
+}
+
+fn parse_algorithm(name: &str, alg: &str) -> Result<Algorithm, String> {
+    Algorithm::from_str(alg).map_err(|_| format!("{}.algorithm {:?} is not supported", name, alg))
+}
+
+fn is_hmac(alg: Algorithm) -> bool {
+    match alg {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => true,
+        _ => false,
+    }
+}
+
+// Build the decoding key for one [[auth.keys]] entry
+fn load_key(name: &str,
+            k: &KeyConfig)
+            -> Result<(Algorithm, DecodingKey<'static>, Vec<u8>), String> {
+    match k.public_key_file {
+        Some(ref path) => {
+            if k.secret.is_some() || k.secret_file.is_some() {
+                return Err(format!("{} can't have both a secret and a public_key_file", name));
+            }
+            let alg = parse_algorithm(name, k.algorithm.as_ref().map_or("RS256", |a| a.as_ref()))?;
+            let pem =
+                fs::read(path).map_err(|e| {
+                                  format!("{}.public_key_file {}: {}", name, path.display(), e)
+                              })?;
+            let decoding =
+                match alg {
+                    Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem),
+                    a if is_hmac(a) => {
+                        return Err(format!("{}.algorithm {:?} needs a secret, not a public key",
+                                           name, a));
+                    }
+                    _ => DecodingKey::from_rsa_pem(&pem),
+                }.map_err(|e| format!("{}.public_key_file {}: {}", name, path.display(), e))?;
+            Ok((alg, decoding.into_static(), pem))
+        }
+        None => {
+            let alg = parse_algorithm(name, k.algorithm.as_ref().map_or("HS256", |a| a.as_ref()))?;
+            if !is_hmac(alg) {
+                return Err(format!("{}.algorithm {:?} needs a public_key_file", name, alg));
+            }
+            let secret = read_secret(name, &k.secret, &k.secret_file)?;
+            Ok((alg, DecodingKey::from_secret(&secret).into_static(), secret))
+        }
+    }
+}
+
+// base64url without padding, as used by JWK
+fn decode_b64url(name: &str, field: &str, value: &Option<String>) -> Result<Vec<u8>, String> {
+    match value {
+        Some(v) => base64::decode_config(v, base64::URL_SAFE_NO_PAD).map_err(|e| {
+                       format!("{} {} is not valid base64url: {}", name, field, e)
+                   }),
+        None => Err(format!("{} is missing {}", name, field)),
+    }
+}
+
+// Turn a JWK into a key.  Returns None for keys that aren't for signing.
+fn load_jwk(name: &str, jwk: &Jwk) -> Result<Option<Key>, String> {
+    if jwk.key_use.as_ref().map_or(false, |u| u != "sig") {
+        return Ok(None);
+    }
+    let id = match jwk.kid {
+        Some(ref kid) if !kid.is_empty() => kid.clone(),
+        _ => return Err(format!("{} has no kid", name)),
+    };
+    let (default_alg, decoding, material) = match jwk.kty.as_ref() {
+        "RSA" => {
+            let n = jwk.n.clone().ok_or_else(|| format!("{} is missing n", name))?;
+            let e = jwk.e.clone().ok_or_else(|| format!("{} is missing e", name))?;
+            // Check they decode now rather than on every request
+            decode_b64url(name, "n", &jwk.n)?;
+            decode_b64url(name, "e", &jwk.e)?;
+            let material = format!("{}.{}", n, e).into_bytes();
+            ("RS256", DecodingKey::from_rsa_components(&n, &e).into_static(), material)
+        }
+        "EC" => {
+            let (alg, len) = match jwk.crv.as_ref().map(|c| c.as_ref()) {
+                Some("P-256") => ("ES256", 32),
+                Some("P-384") => ("ES384", 48),
+                other => return Err(format!("{} has unsupported curve {:?}", name, other)),
+            };
+            let x = decode_b64url(name, "x", &jwk.x)?;
+            let y = decode_b64url(name, "y", &jwk.y)?;
+            if x.len() != len || y.len() != len {
+                return Err(format!("{} x and y must be {} bytes", name, len));
+            }
+            // ring wants the uncompressed point: 0x04 || x || y
+            let mut point = vec![0x04];
+            point.extend(x);
+            point.extend(y);
+            (alg, DecodingKey::from_ec_der(&point).into_static(), point)
+        }
+        other => return Err(format!("{} has unsupported kty {:?}", name, other)),
+    };
+    let algorithm = parse_algorithm(name, jwk.alg.as_ref().map_or(default_alg, |a| a.as_ref()))?;
+    if is_hmac(algorithm) {
+        return Err(format!("{} can't use {:?} with a public key", name, algorithm));
+    }
+    Ok(Some(Key { id, algorithm, expires: None, decoding, material }))
+}
+
+impl KeySet {
+    /// Read and decode every configured key, reporting all of the problems
+    pub fn load(auth: &AuthConfig) -> Result<KeySet, Vec<String>> {
+        let mut keys: Vec<Key> = vec![];
+        let mut problems = vec![];
+
+        if auth.secret.is_some() || auth.secret_file.is_some() {
+            match read_secret("auth", &auth.secret, &auth.secret_file) {
+                Ok(secret) => keys.push(Key { id: DEFAULT_KEY_ID.into(),
+                                              algorithm: Algorithm::HS256,
+                                              expires: None,
+                                              decoding:
+                                                  DecodingKey::from_secret(&secret).into_static(),
+                                              material: secret }),
+                Err(e) => problems.push(e),
+            }
+        }
+        for (i, k) in auth.keys.iter().enumerate() {
+            let name = format!("auth.keys[{}]", i);
+            if k.id.is_empty() {
+                problems.push(format!("{}.id must be set", name));
+            } else if k.id == DEFAULT_KEY_ID {
+                problems.push(format!("{}.id {:?} is reserved for auth.secret", name, k.id));
+            } else if auth.keys[..i].iter().any(|other| other.id == k.id) {
+                problems.push(format!("{}.id {:?} is used more than once", name, k.id));
+            }
+            let expires = match k.expires {
+                Some(ref e) => match DateTime::parse_from_rfc3339(e) {
+                    Ok(d) => Some(d.with_timezone(&Utc)),
+                    Err(err) => {
+                        problems.push(format!("{}.expires {:?} is not an RFC 3339 time: {}",
+                                              name, e, err));
+                        None
+                    }
+                },
+                None => None,
+            };
+            match load_key(&name, k) {
+                Ok((algorithm, decoding, material)) => {
+                    keys.push(Key { id: k.id.clone(), algorithm, expires, decoding, material })
+                }
+                Err(e) => problems.push(e),
+            }
+        }
+        if let Some(ref path) = auth.jwks_file {
+            let jwks = fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|s| {
+                           serde_json::from_str::<Jwks>(&s).map_err(|e| e.to_string())
+                       });
+            match jwks {
+                Ok(jwks) => {
+                    for (i, jwk) in jwks.keys.iter().enumerate() {
+                        let name = format!("{} key {}", path.display(), i);
+                        match load_jwk(&name, jwk) {
+                            Ok(Some(key)) => {
+                                if keys.iter().any(|k| k.id == key.id) {
+                                    problems.push(format!("{} kid {:?} is used more than once",
+                                                          name, key.id));
+                                }
+                                keys.push(key);
+                            }
+                            Ok(None) => {}
+                            Err(e) => problems.push(e),
+                        }
+                    }
+                }
+                Err(e) => problems.push(format!("auth.jwks_file {}: {}", path.display(), e)),
+            }
+        }
+
+        if keys.is_empty() && problems.is_empty() {
+            problems.push("auth.secret, auth.secret_file, auth.keys or auth.jwks_file must be set"
+                                                                                        .into());
+        } else if problems.is_empty() && !keys.iter().any(|k| k.is_active(&Utc::now())) {
+            problems.push("every key in auth.keys has expired".into());
+        }
+        if problems.is_empty() {
+            Ok(KeySet { keys, leeway: auth.leeway })
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// The key with this id, whether or not it has expired
+    pub fn get(&self, id: &str) -> Option<&Key> { self.keys.iter().find(|k| k.id == id) }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Key> { self.keys.iter() }
+
+    /// The keys a token signed with `alg` should be checked against
+    pub fn candidates(&self, kid: Option<&str>, alg: Algorithm, now: &DateTime<Utc>) -> Vec<&Key> {
+        self.keys
+            .iter()
+            .filter(|k| k.is_active(now) && k.algorithm == alg)
+            .filter(|k| kid.map_or(true, |kid| kid == k.id))
+            .collect()
+    }
+
+    /// Describe the keys that were added, removed or changed
+    pub fn diff(&self, new: &KeySet) -> Vec<String> {
+        let mut changes = vec![];
+        for key in &self.keys {
+            match new.keys.iter().find(|k| k.id == key.id) {
+                None => changes.push(format!("auth key {:?}: removed", key.id)),
+                Some(k) if k.material != key.material || k.algorithm != key.algorithm => {
+                    changes.push(format!("auth key {:?}: key changed", key.id))
+                }
+                Some(_) => {}
+            }
+        }
+        for key in &new.keys {
+            match self.keys.iter().find(|k| k.id == key.id) {
+                None => changes.push(format!("auth key {:?}: added", key.id)),
+                Some(k) if k.expires != key.expires => {
+                    changes.push(format!("auth key {:?}: expires {:?} -> {:?}",
+                                         key.id, k.expires, key.expires))
+                }
+                Some(_) => {}
+            }
+        }
+        changes
+    }
+}
+
+/// Why a request was refused.  Token problems are the client's fault and
+/// answered with 401, anything else means the server is misconfigured.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuthError {
+    /// No Authorization header
+    Missing,
+    /// The header isn't `Bearer <token>` or the token isn't a JWT
+    Malformed(String),
+    /// The token names a key or algorithm we don't have, or the key expired
+    UnknownKey,
+    Expired,
+    InvalidSignature,
+    /// The token decoded but its claims were rejected
+    Invalid(String),
+    /// The token is valid but its issuer may not use this route
+    Forbidden(String),
+    /// The server can't check tokens at all
+    Misconfigured(String),
+}
+
+impl AuthError {
+    pub fn status(&self) -> Status {
+        match *self {
+            AuthError::Misconfigured(_) => Status::InternalServerError,
+            AuthError::Forbidden(_) => Status::Forbidden,
+            _ => Status::Unauthorized,
+        }
+    }
+
+    /// Machine readable name, also used as the RFC 6750 error code in
+    /// `WWW-Authenticate`
+    pub fn code(&self) -> &'static str {
+        match *self {
+            AuthError::Missing => "missing_token",
+            AuthError::Misconfigured(_) => "server_error",
+            AuthError::Forbidden(_) => "forbidden",
+            _ => "invalid_token",
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            AuthError::Missing => write!(f, "JWT token missing from request"),
+            AuthError::Malformed(ref e) => write!(f, "malformed Authorization header: {}", e),
+            AuthError::UnknownKey => write!(f, "JWT signing key is unknown or expired"),
+            AuthError::Expired => write!(f, "JWT token has expired"),
+            AuthError::InvalidSignature => write!(f, "JWT signature is invalid"),
+            AuthError::Invalid(ref e) => write!(f, "JWT token is invalid: {}", e),
+            AuthError::Forbidden(ref e) => write!(f, "forbidden: {}", e),
+            AuthError::Misconfigured(ref e) => write!(f, "authentication is misconfigured: {}", e),
+        }
+    }
+}
+
+// Rocket only hands the status of a failed guard to the catchers, so the
+// error itself is stashed on the request.
+struct Failure(Option<AuthError>);
+
+/// The error the Jwt guard refused this request with, if any
+pub fn failure(request: &Request<'_>) -> Option<AuthError> {
+    request.local_cache(|| Failure(None)).0.clone()
+}
+
+/// Pull the token out of an `Authorization: Bearer <token>` header
+pub fn bearer_token(header: &str) -> Result<&str, AuthError> {
+    let mut parts = header.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(scheme), Some(token), None) if scheme.eq_ignore_ascii_case("bearer") => Ok(token),
+        // The "scheme" may be a bare token, don't log it
+        (Some(scheme), ..) if !scheme.eq_ignore_ascii_case("bearer") => {
+            Err(AuthError::Malformed("unsupported scheme, expected Bearer".into()))
+        }
+        (_, None, _) => Err(AuthError::Malformed("no token after Bearer".into())),
+        _ => Err(AuthError::Malformed("expected \"Bearer <token>\"".into())),
+    }
+}
+
+/// Check a token the way the Jwt guard does
+pub fn verify(key_set: &KeySet, token: &str, now: &DateTime<Utc>) -> Result<Jwt, AuthError> {
+    let header = decode_header(token).map_err(|e| AuthError::Malformed(e.to_string()))?;
+    let keys = key_set.candidates(header.kid.as_ref().map(|k| k.as_ref()), header.alg, now);
+    if keys.is_empty() {
+        println!("jwt names unknown or expired key: {:?} {:?}", header.kid, header.alg);
+        return Err(AuthError::UnknownKey);
+    }
+    let mut last_err = AuthError::InvalidSignature;
+    for key in keys {
+        // Set the default params for validation.  Each key only accepts its
+        // own algorithm so an HS256 token can never be checked against a
+        // public key.
+        let mut validate = Validation::new(key.algorithm);
+        validate.leeway = key_set.leeway;
+        validate.validate_nbf = false;
+        match decode::<Claims>(token, &key.decoding, &validate) {
+            Ok(data) => return Ok(Jwt { claims: data.claims, key_id: key.id.clone() }),
+            Err(e) => {
+                last_err = match e.kind() {
+                    ErrorKind::InvalidSignature => AuthError::InvalidSignature,
+                    // The signature checked out so no other key will do better
+                    ErrorKind::ExpiredSignature => return Err(AuthError::Expired),
+                    ErrorKind::InvalidToken
+                    | ErrorKind::Base64(_)
+                    | ErrorKind::Json(_)
+                    | ErrorKind::Utf8(_) => AuthError::Malformed(e.to_string()),
+                    _ => AuthError::Invalid(e.to_string()),
+                }
+            }
+        }
+    }
+    Err(last_err)
+}
+
+fn authenticate(request: &Request<'_>) -> Result<Jwt, AuthError> {
+    // The keys are decoded once when the config is loaded or reloaded
+    let config = match request.guard::<State<'_, Arc<LiveConfig>>>() {
+        Outcome::Success(live) => live.current(),
+        _ => return Err(AuthError::Misconfigured("configuration is not available".into())),
+    };
+    let token =
+        bearer_token(request.headers().get_one("Authorization").ok_or(AuthError::Missing)?)?;
+    let jwt = verify(&config.auth.key_set, token, &Utc::now())?;
+    println!("audit: {} {} authorized for {} by key {}",
+             request.method(),
+             request.uri(),
+             jwt.claims.iss,
+             jwt.key_id);
+    Ok(jwt)
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Jwt {
+    type Error = AuthError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Jwt, Self::Error> {
+        match authenticate(request) {
+            Ok(jwt) => Outcome::Success(jwt),
+            Err(e) => {
+                println!("jwt rejected for {} {}: {}", request.method(), request.uri(), e);
+                request.local_cache(|| Failure(Some(e.clone())));
+                Outcome::Failure((e.status(), e))
+            }
+        }
+    }
+}
+
+/// A token from one of auth.admin_issuers, for the /admin routes
+pub struct Admin(pub Jwt);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = AuthError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, Self::Error> {
+        let jwt = match request.guard::<Jwt>() {
+            Outcome::Success(jwt) => jwt,
+            Outcome::Failure(f) => return Outcome::Failure(f),
+            Outcome::Forward(f) => return Outcome::Forward(f),
+        };
+        let admin = match request.guard::<State<'_, Arc<LiveConfig>>>() {
+            Outcome::Success(live) => live.current().auth.admin_issuers.contains(&jwt.claims.iss),
+            _ => false,
+        };
+        if admin {
+            return Outcome::Success(Admin(jwt));
+        }
+        let e = AuthError::Forbidden(format!("{} is not in auth.admin_issuers", jwt.claims.iss));
+        println!("jwt rejected for {} {}: {}", request.method(), request.uri(), e);
+        request.local_cache(|| Failure(Some(e.clone())));
+        Outcome::Failure((e.status(), e))
+    }
+}
+
+#[test]
+fn test_key_candidates() {
+    use crate::config::KeyConfig;
+
+    let auth = AuthConfig { secret: Some("c3VwZXJfc2VjcmV0".into()),
+                            keys: vec![KeyConfig { id: "old".into(),
+                                                   secret: Some("b2xk".into()),
+                                                   expires:
+                                                       Some("2001-01-01T00:00:00Z".into()),
+                                                   ..Default::default() },
+                                       KeyConfig { id: "new".into(),
+                                                   secret: Some("bmV3".into()),
+                                                   ..Default::default() }],
+                            ..Default::default() };
+    let keys = KeySet::load(&auth).unwrap();
+    let now = Utc::now();
+    let ids = |kid| {
+        keys.candidates(kid, Algorithm::HS256, &now)
+            .iter()
+            .map(|k| k.id.clone())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(ids(None), vec!["default".to_string(), "new".to_string()]);
+    assert_eq!(ids(Some("new")), vec!["new".to_string()]);
+    // Expired and unknown keys can't validate anything
+    assert!(ids(Some("old")).is_empty());
+    assert!(ids(Some("missing")).is_empty());
+    // None of the keys can check an RS256 token
+    assert!(keys.candidates(None, Algorithm::RS256, &now).is_empty());
+}
+
+#[test]
+fn test_load_jwk() {
+    let jwks: Jwks = serde_json::from_str(r#"{"keys": [
+        {"kty": "RSA", "kid": "rsa-1", "use": "sig", "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw", "e": "AQAB"},
+        {"kty": "EC", "kid": "ec-1", "crv": "P-256", "x": "f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU", "y": "x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0"},
+        {"kty": "RSA", "kid": "enc-1", "use": "enc", "n": "AQAB", "e": "AQAB"}
+    ]}"#).unwrap();
+
+    let rsa = load_jwk("rsa", &jwks.keys[0]).unwrap().unwrap();
+    assert_eq!(rsa.id, "rsa-1");
+    assert_eq!(rsa.algorithm, Algorithm::RS256);
+    let ec = load_jwk("ec", &jwks.keys[1]).unwrap().unwrap();
+    assert_eq!(ec.algorithm, Algorithm::ES256);
+    assert_eq!(ec.material.len(), 65);
+    // Encryption keys are skipped
+    assert!(load_jwk("enc", &jwks.keys[2]).unwrap().is_none());
+}
+
+#[test]
+fn test_key_set_problems() {
+    use crate::config::KeyConfig;
+
+    let auth = AuthConfig { keys: vec![KeyConfig { id: "a".into(),
+                                                   secret: Some("YQ==".into()),
+                                                   expires: Some("next tuesday".into()),
+                                                   ..Default::default() },
+                                       KeyConfig { id: "a".into(), ..Default::default() }],
+                            ..Default::default() };
+    let problems = KeySet::load(&auth).unwrap_err();
+    assert_eq!(problems.len(), 3);
+    assert!(problems[0].contains("RFC 3339"));
+    assert!(problems[1].contains("more than once"));
+}
+
+#[test]
+fn test_bearer_token() {
+    assert_eq!(bearer_token("Bearer abc"), Ok("abc"));
+    assert_eq!(bearer_token("bearer   abc "), Ok("abc"));
+    for header in &["", "Bearer", "Bearer ", "abc", "Basic abc", "Bearer a b"] {
+        match bearer_token(header) {
+            Err(AuthError::Malformed(_)) => {}
+            other => panic!("{:?} gave {:?}", header, other),
+        }
+    }
+}
+
+#[test]
+fn test_jwt_guard() {
+    use crate::config::{Config, ConfigSource};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use rocket::{http::Header as HttpHeader, local::Client};
+
+    #[get("/")]
+    fn index(_web_token: Jwt) -> &'static str { "ok" }
+
+    #[get("/admin")]
+    fn admin(_admin: Admin) -> &'static str { "ok" }
+
+    #[catch(401)]
+    fn unauthorized(req: &Request<'_>) -> Option<crate::error::ApiError> {
+        failure(req).map(From::from)
+    }
+
+    #[catch(403)]
+    fn forbidden(req: &Request<'_>) -> Option<crate::error::ApiError> {
+        failure(req).map(From::from)
+    }
+
+    let mut config = Config::default();
+    config.auth.secret = Some("c3VwZXJfc2VjcmV0".into());
+    config.auth.key_set = KeySet::load(&config.auth).unwrap();
+    let source = ConfigSource { path: PathBuf::new(), required: false, volume: None };
+    let rocket = rocket::ignite().mount("/", routes![index, admin])
+                                 .register(catchers![unauthorized, forbidden])
+                                 .manage(Arc::new(LiveConfig::new(source, config)));
+    let client = Client::new(rocket).unwrap();
+
+    let now = Utc::now().timestamp() as u64;
+    let token_from = |iss: &str, secret: &[u8], exp: u64| {
+        let claims = Claims { iss: iss.into(), iat: now, exp, qsh: String::new() };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(secret)).unwrap()
+    };
+    let token = |secret: &[u8], exp: u64| token_from("test", secret, exp);
+    let valid = token(b"super_secret", now + 300);
+    let expired = token(b"super_secret", now - 3600);
+    let forged = token(b"not_the_secret", now + 300);
+
+    let cases = vec![(None, "missing_token"),
+                     (Some("".to_string()), "invalid_token"),
+                     (Some("Bearer".into()), "invalid_token"),
+                     (Some("Bearer ".into()), "invalid_token"),
+                     (Some(valid.clone()), "invalid_token"),
+                     (Some(format!("Basic {}", valid)), "invalid_token"),
+                     (Some(format!("Bearer {} extra", valid)), "invalid_token"),
+                     (Some("Bearer not.a.jwt".into()), "invalid_token"),
+                     (Some("Bearer \u{2603}".into()), "invalid_token"),
+                     (Some(format!("Bearer {}", expired)), "invalid_token"),
+                     (Some(format!("Bearer {}", forged)), "invalid_token")];
+    for (header, code) in cases {
+        let mut req = client.get("/");
+        if let Some(ref h) = header {
+            req.add_header(HttpHeader::new("Authorization", h.clone()));
+        }
+        let mut res = req.dispatch();
+        assert_eq!(res.status(), Status::Unauthorized, "{:?}", header);
+        let www = res.headers().get_one("WWW-Authenticate").unwrap().to_string();
+        assert!(www.starts_with("Bearer error="), "{:?}", header);
+        let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        assert_eq!(body["code"], code, "{:?}", header);
+    }
+
+    let mut res = client.get("/")
+                        .header(HttpHeader::new("Authorization", format!("Bearer {}", valid)))
+                        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(res.body_string(), Some("ok".into()));
+
+    // Only admin issuers may use the /admin routes
+    let bearer = |token: &str| HttpHeader::new("Authorization", format!("Bearer {}", token));
+    let mut res = client.get("/admin").header(bearer(&valid)).dispatch();
+    assert_eq!(res.status(), Status::Forbidden);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["code"], "forbidden");
+    let admin_token = token_from("admin", b"super_secret", now + 300);
+    let res = client.get("/admin").header(bearer(&admin_token)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+}
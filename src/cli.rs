@@ -0,0 +1,404 @@
+//! Admin subcommands, so operators don't need curl and hand made tokens.
+//!
+//! They work on the gluster volume directly through the same code as the
+//! HTTP routes, including the locks, so they're safe to run next to the
+//! servers.  There's no mode that goes through a running server over HTTP.
+//! The clean ups the leader runs itself are refused while an instance leads,
+//! unless they're forced.
+use std::{io::{self, Read},
+          path::Path,
+          str::FromStr};
+
+use chrono::Utc;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use gfapi_sys::gluster::Gluster;
+use gluster::peer::peer_list;
+
+use crate::{auth::{self, DEFAULT_KEY_ID},
+            config::Config,
+            create, delete,
+            error::{ApiError, ApiResult},
+            expand, get_subdir_name, gids, leader,
+            lock::Locks,
+            quota, token, topology,
+            volume::{volume_ids, VolumeId},
+            volume_info, CreateVolumeRequest, Snapshot};
+
+/// Every admin subcommand
+pub fn subcommands() -> Vec<App<'static, 'static>> {
+    let id = || Arg::with_name("id").help("The volume id").required(true);
+    let dry_run = |help| Arg::with_name("dry-run").long("dry-run").help(help);
+    let force = || {
+        Arg::with_name("force").long("force")
+                               .help("Run even though a piragua instance leads and cleans up \
+                                      itself")
+    };
+    let method = || Arg::with_name("method").long("method").takes_value(true).default_value("GET");
+    let path = || Arg::with_name("path").long("path").takes_value(true).default_value("/volumes");
+    vec![SubCommand::with_name("volume")
+             .about("Manage volumes")
+             .subcommand(SubCommand::with_name("list").about("List every volume's id and name"))
+             .subcommand(SubCommand::with_name("info").about("Show a volume as heketi would")
+                                                       .arg(id()))
+             .subcommand(SubCommand::with_name("create")
+                             .about("Create a volume")
+                             .arg(Arg::with_name("size").long("size")
+                                                        .takes_value(true)
+                                                        .help("Size in GB.  Defaults to \
+                                                               quota.default_size"))
+                             .arg(Arg::with_name("name").long("name").takes_value(true))
+                             .arg(Arg::with_name("class").long("class")
+                                                         .takes_value(true)
+                                                         .help("Use the owner and permissions \
+                                                                of this volumes.classes entry"))
+                             .arg(Arg::with_name("uid").long("uid").takes_value(true))
+                             .arg(Arg::with_name("gid").long("gid").takes_value(true))
+                             .arg(Arg::with_name("mode").long("mode")
+                                                        .takes_value(true)
+                                                        .help("Octal permissions, ie 2770")))
+             .subcommand(SubCommand::with_name("delete").about("Delete a volume").arg(id()))
+             .subcommand(SubCommand::with_name("expand")
+                             .about("Grow a volume's quota")
+                             .arg(id())
+                             .arg(Arg::with_name("size").long("size")
+                                                        .takes_value(true)
+                                                        .required(true)
+                                                        .help("GB to add"))),
+         SubCommand::with_name("quota")
+             .about("Manage the quotas on the gluster volume")
+             .subcommand(SubCommand::with_name("cleanup")
+                             .about("Remove the quotas of volumes that were deleted")
+                             .arg(dry_run("Only list the stale quotas"))
+                             .arg(force()))
+             .subcommand(SubCommand::with_name("audit")
+                             .about("Report volumes without a quota or over it and quotas \
+                                     of deleted volumes")),
+         SubCommand::with_name("reconcile")
+             .about("Remove the quotas and gid claims of volumes that were deleted")
+             .arg(dry_run("Only list what would be removed"))
+             .arg(force()),
+         SubCommand::with_name("token")
+             .about("Make and check tokens for heketi clients")
+             .subcommand(SubCommand::with_name("generate")
+                             .about("Print a token signed with a configured key")
+                             .arg(Arg::with_name("iss").long("iss")
+                                                       .takes_value(true)
+                                                       .default_value("admin")
+                                                       .help("Issuer, heketi's restuser"))
+                             .arg(method())
+                             .arg(path())
+                             .arg(Arg::with_name("lifetime").long("lifetime")
+                                                            .takes_value(true)
+                                                            .default_value("600")
+                                                            .help("Seconds the token is valid \
+                                                                   for"))
+                             .arg(Arg::with_name("key").long("key")
+                                                       .takes_value(true)
+                                                       .default_value(DEFAULT_KEY_ID)
+                                                       .help("Id of the key in auth.keys or \
+                                                              auth.jwks_file to sign with.  \
+                                                              The default is auth.secret"))
+                             .arg(Arg::with_name("private-key").long("private-key")
+                                                               .takes_value(true)
+                                                               .help("PEM private key for an \
+                                                                      RS256 or ES256 key")))
+             .subcommand(SubCommand::with_name("verify")
+                             .about("Explain whether piragua accepts a token and why not")
+                             .arg(Arg::with_name("token").help("The token, or \"Bearer \
+                                                                <token>\".  Read from stdin \
+                                                                if not given"))
+                             .arg(Arg::with_name("method").long("method")
+                                                          .takes_value(true)
+                                                          .help("Check the qsh is for this \
+                                                                 method and --path"))
+                             .arg(Arg::with_name("path").long("path")
+                                                        .takes_value(true)
+                                                        .requires("method")))]
+}
+
+// Print the error of a subcommand and turn it into an exit code
+fn exit_code(result: ApiResult<()>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("{}: {}", e.code(), e);
+            1
+        }
+    }
+}
+
+// A number given on the command line
+fn number<T: FromStr>(matches: &ArgMatches<'_>, name: &str) -> ApiResult<Option<T>> {
+    match matches.value_of(name) {
+        Some(value) => value.parse().map(Some).map_err(|_| {
+                                                  ApiError::InvalidInput(format!("--{} {:?} is \
+                                                                                  not a number",
+                                                                                 name, value))
+                                              }),
+        None => Ok(None),
+    }
+}
+
+fn volume_id(matches: &ArgMatches<'_>) -> ApiResult<VolumeId> {
+    VolumeId::from_str(matches.value_of("id").unwrap_or_default())
+}
+
+/// Run the subcommand that needs gluster, if one was given.  Returns the
+/// exit code
+pub fn run(config: &Config,
+           gluster: &Gluster,
+           vol_name: &str,
+           matches: &ArgMatches<'_>)
+           -> Option<i32> {
+    let locks = Locks::new(vol_name);
+    let result = match matches.subcommand() {
+        ("volume", Some(m)) => match m.subcommand() {
+            ("list", Some(_)) => list_volumes(gluster),
+            ("info", Some(m)) => show_volume(config, gluster, vol_name, m),
+            ("create", Some(m)) => create_volume(config, gluster, vol_name, m),
+            ("delete", Some(m)) => {
+                volume_id(m).and_then(|id| delete(config, gluster, vol_name, &locks, &id))
+            }
+            ("expand", Some(m)) => expand_volume(config, gluster, vol_name, &locks, m),
+            _ => Err(ApiError::InvalidInput(m.usage().into())),
+        },
+        ("quota", Some(m)) => match m.subcommand() {
+            ("cleanup", Some(m)) => match check_leader(config, gluster, &locks, m) {
+                Ok(()) => return Some(cleanup_quotas(gluster, vol_name, m.is_present("dry-run"))),
+                Err(e) => Err(e),
+            },
+            ("audit", Some(_)) => audit_quotas(config, gluster, vol_name),
+            _ => Err(ApiError::InvalidInput(m.usage().into())),
+        },
+        ("reconcile", Some(m)) => check_leader(config, gluster, &locks, m).and_then(|()| {
+                                      reconcile(gluster, vol_name, m.is_present("dry-run"))
+                                  }),
+        _ => return None,
+    };
+    Some(exit_code(result))
+}
+
+// Refuse to clean up what the leader cleans up itself, which would race it.
+// Dry runs and --force go ahead
+fn check_leader(config: &Config,
+                gluster: &Gluster,
+                locks: &Locks,
+                matches: &ArgMatches<'_>)
+                -> ApiResult<()> {
+    if matches.is_present("dry-run") || matches.is_present("force") {
+        return Ok(());
+    }
+    match leader::current_leader(&config.lock, gluster, locks)? {
+        Some(lease) => Err(ApiError::Conflict(format!("{} on {} is the leader and cleans up \
+                                                       itself.  Use --force to run this here \
+                                                       anyway",
+                                                      lease.instance, lease.host))),
+        None => Ok(()),
+    }
+}
+
+fn list_volumes(gluster: &Gluster) -> ApiResult<()> {
+    for id in volume_ids(gluster)? {
+        let name = get_subdir_name(id.as_str().as_ref(), gluster)?.unwrap_or_default();
+        println!("{}\t{}", id, name);
+    }
+    Ok(())
+}
+
+fn show_volume(config: &Config,
+               gluster: &Gluster,
+               vol_name: &str,
+               matches: &ArgMatches<'_>)
+               -> ApiResult<()> {
+    let id = volume_id(matches)?;
+    let name = match get_subdir_name(id.as_str().as_ref(), gluster)? {
+        Some(name) => name,
+        None => return Err(ApiError::NotFound(format!("Unable to find volume {}", id))),
+    };
+    let cluster_id = topology::cluster_id(&config.gluster, vol_name)?;
+    let info = volume_info(gluster, vol_name, &cluster_id, &id, &name, &peer_list()?)?;
+    println!("{}", serde_json::to_string_pretty(&info)?);
+    Ok(())
+}
+
+fn create_volume(config: &Config,
+                 gluster: &Gluster,
+                 vol_name: &str,
+                 matches: &ArgMatches<'_>)
+                 -> ApiResult<()> {
+    let input = CreateVolumeRequest { size: number(matches, "size")?.unwrap_or(0),
+                                      clusters: None,
+                                      name: matches.value_of("name").unwrap_or_default().into(),
+                                      durability: None,
+                                      gid: number(matches, "gid")?,
+                                      uid: number(matches, "uid")?,
+                                      mode: matches.value_of("mode").map(String::from),
+                                      setgid: None,
+                                      snapshot: Snapshot { enable: None, factor: None },
+                                      tags: Default::default(),
+                                      acl: Default::default(),
+                                      selinux_categories: None };
+    let class = matches.value_of("class").unwrap_or_default();
+    let (id, name) = create(config, gluster, vol_name, class, &input)?;
+    println!("Created {}/{}/{}", vol_name, id, name);
+    Ok(())
+}
+
+fn expand_volume(config: &Config,
+                 gluster: &Gluster,
+                 vol_name: &str,
+                 locks: &Locks,
+                 matches: &ArgMatches<'_>)
+                 -> ApiResult<()> {
+    let id = volume_id(matches)?;
+    let size = number(matches, "size")?.unwrap_or(0);
+    let _lock = locks.volume(&config.lock, gluster, &id, &format!("expand {}", id))?;
+    if !gluster.exists(id.as_str().as_ref())? {
+        return Err(ApiError::NotFound(format!("Unable to find volume {}", id)));
+    }
+    expand(config, gluster, vol_name, &id, size)?;
+    println!("Expanded {} by {}GB", id, size);
+    Ok(())
+}
+
+// piragua quota cleanup.  Returns the exit code
+fn cleanup_quotas(gluster: &Gluster, vol_name: &str, dry_run: bool) -> i32 {
+    match quota::remove_stale(gluster, vol_name, dry_run) {
+        Ok((removed, failed)) => {
+            for path in &removed {
+                println!("{} {}", if dry_run { "stale" } else { "removed" }, path.display());
+            }
+            for path in &failed {
+                println!("failed {}", path.display());
+            }
+            println!("{} stale quotas {}, {} failed",
+                     removed.len(),
+                     if dry_run { "found" } else { "removed" },
+                     failed.len());
+            if failed.is_empty() {
+                0
+            } else {
+                1
+            }
+        }
+        Err(e) => {
+            println!("Unable to clean up quotas: {}", e);
+            1
+        }
+    }
+}
+
+// Fails if anything needs looking at
+fn audit_quotas(config: &Config, gluster: &Gluster, vol_name: &str) -> ApiResult<()> {
+    let mut problems = 0;
+    for id in volume_ids(gluster)? {
+        match quota::get_usage(&config.quota, gluster, vol_name, &id)? {
+            None => {
+                println!("no quota {}", id);
+                problems += 1;
+            }
+            Some(usage) if usage.used > usage.hard_limit => {
+                println!("over quota {}: {} of {} bytes", id, usage.used, usage.hard_limit);
+                problems += 1;
+            }
+            Some(_) => {}
+        }
+    }
+    let (stale, _) = quota::remove_stale(gluster, vol_name, true)?;
+    for path in &stale {
+        println!("stale {}", path.display());
+    }
+    problems += stale.len();
+    if problems > 0 {
+        return Err(ApiError::Conflict(format!("{} quota problems found", problems)));
+    }
+    println!("Every volume has a quota it's within");
+    Ok(())
+}
+
+// Clean up after volumes that were deleted without their quota or gid
+fn reconcile(gluster: &Gluster, vol_name: &str, dry_run: bool) -> ApiResult<()> {
+    let done = if dry_run { "would remove" } else { "removed" };
+    let (removed, failed) = quota::remove_stale(gluster, vol_name, dry_run)?;
+    for path in &removed {
+        println!("{} stale quota {}", done, path.display());
+    }
+    for (gid, id) in gids::release_orphans(gluster, dry_run)? {
+        match id {
+            Some(id) => println!("{} gid claim {} of {}", done, gid, id),
+            None => println!("{} empty gid claim {}", done, gid),
+        }
+    }
+    if !failed.is_empty() {
+        let msg = format!("{} stale quotas couldn't be removed", failed.len());
+        return Err(ApiError::BackendUnavailable(msg));
+    }
+    Ok(())
+}
+
+/// piragua token.  Returns the exit code
+pub fn token(config: &Config, matches: &ArgMatches<'_>) -> i32 {
+    match matches.subcommand() {
+        ("generate", Some(m)) => match generate_token(config, m) {
+            Ok(token) => {
+                println!("{}", token);
+                0
+            }
+            Err(e) => {
+                println!("Unable to make a token: {}", e);
+                1
+            }
+        },
+        ("verify", Some(m)) => {
+            let token = match m.value_of("token") {
+                Some(token) => token.to_string(),
+                None => {
+                    let mut token = String::new();
+                    if let Err(e) = io::stdin().read_to_string(&mut token) {
+                        println!("Unable to read the token: {}", e);
+                        return 1;
+                    }
+                    token
+                }
+            };
+            let token = token.trim();
+            // Take the header value as well as the bare token
+            let token = auth::bearer_token(token).unwrap_or(token);
+            let request = m.value_of("method")
+                           .map(|method| (method, m.value_of("path").unwrap_or("/volumes")));
+            let (accepted, lines) =
+                token::explain(&config.auth.key_set, token, request, &Utc::now());
+            for line in lines {
+                println!("{}", line);
+            }
+            if accepted {
+                0
+            } else {
+                1
+            }
+        }
+        _ => {
+            println!("{}", matches.usage());
+            1
+        }
+    }
+}
+
+fn generate_token(config: &Config, matches: &ArgMatches<'_>) -> Result<String, String> {
+    let lifetime = number(matches, "lifetime").map_err(|e| e.to_string())?.unwrap_or(600);
+    let key = matches.value_of("key").unwrap_or(DEFAULT_KEY_ID);
+    let signer =
+        token::signer(&config.auth.key_set, key, matches.value_of("private-key").map(Path::new))?;
+    let now = Utc::now();
+    let token = token::generate(&signer,
+                                matches.value_of("iss").unwrap_or_default(),
+                                matches.value_of("method").unwrap_or_default(),
+                                matches.value_of("path").unwrap_or_default(),
+                                &now,
+                                lifetime)?;
+    // Catches a private key that doesn't pair with the configured public key
+    auth::verify(&config.auth.key_set, &token, &now).map_err(|e| {
+                                                        format!("piragua would reject it: {}", e)
+                                                    })?;
+    Ok(token)
+}
@@ -0,0 +1,844 @@
+//! Piragua configuration.
+//!
+//! Settings are read from a single TOML file (by default
+//! `/etc/piragua/piragua.toml`).  Every setting has a default and a few of
+//! them can be overridden with environment variables so existing
+//! deployments that only use `/etc/piragua/environment` keep working.
+use std::{collections::{BTreeMap, BTreeSet},
+          env, fmt, fs,
+          net::IpAddr,
+          path::{Path, PathBuf},
+          str::FromStr,
+          sync::{Arc, RwLock}};
+
+use libc::mode_t;
+use serde::de::{self, Deserializer};
+
+use crate::{auth::KeySet, selinux::check_label};
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/piragua/piragua.toml";
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub gluster: GlusterConfig,
+    pub server: ServerConfig,
+    pub auth: AuthConfig,
+    pub volumes: VolumeConfig,
+    pub quota: QuotaConfig,
+    pub nodes: NodesConfig,
+    pub lock: LockConfig,
+    pub leader: LeaderConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GlusterConfig {
+    /// The gluster volume to manage
+    pub volume: String,
+    /// Address glusterd is listening on
+    pub host: String,
+    pub port: u16,
+    /// glusterd's working directory.  Peer and volume info is read from here
+    pub state_dir: PathBuf,
+    /// The id heketi knows the cluster by.  Defaults to the volume-id of the
+    /// gluster volume
+    pub cluster_id: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Base64 encoded HS256 secret
+    pub secret: Option<String>,
+    /// File containing the base64 encoded HS256 secret
+    pub secret_file: Option<PathBuf>,
+    /// Additional keys, selected by the `kid` header of a token
+    pub keys: Vec<KeyConfig>,
+    /// JSON Web Key Set with RS256/ES256 public keys
+    pub jwks_file: Option<PathBuf>,
+    /// Issuers whose tokens may use the /admin routes
+    pub admin_issuers: Vec<String>,
+    /// Seconds a token is still accepted after it expires, for clock skew
+    pub leeway: u64,
+    /// The decoded keys.  Filled in by `ConfigSource::load`
+    #[serde(skip)]
+    pub key_set: KeySet,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyConfig {
+    /// Matched against the `kid` header of incoming tokens
+    pub id: String,
+    /// Base64 encoded HMAC secret
+    pub secret: Option<String>,
+    /// File containing the base64 encoded HMAC secret
+    pub secret_file: Option<PathBuf>,
+    /// PEM encoded RSA or EC public key, for RS256 and ES256 tokens
+    pub public_key_file: Option<PathBuf>,
+    /// HS256, HS384 or HS512 for secrets.  RS256, RS384, RS512, PS256,
+    /// PS384, PS512, ES256 or ES384 for public keys.  Defaults to HS256 or
+    /// RS256
+    pub algorithm: Option<String>,
+    /// RFC 3339 time after which this key is no longer accepted,
+    /// ie "2019-06-01T00:00:00Z"
+    pub expires: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct VolumeConfig {
+    /// Owner of new volume directories when the request doesn't have one
+    pub default_uid: u32,
+    /// Group id applied to new volumes when the request doesn't have one
+    pub default_gid: Option<u32>,
+    /// Permissions applied to new volume directories, ie "0570"
+    #[serde(deserialize_with = "deserialize_mode")]
+    pub default_mode: mode_t,
+    /// Set the setgid bit so files made in the volume get its group
+    pub setgid: bool,
+    /// Range to allocate a unique gid from when neither the request nor
+    /// default_gid has one, like heketi's gidMin and gidMax
+    pub gid_min: Option<u32>,
+    pub gid_max: Option<u32>,
+    /// SELinux label for new volume directories, ie
+    /// "system_u:object_r:container_file_t:s0".  Not labelled if unset
+    pub selinux_label: Option<String>,
+    /// Overrides for a StorageClass, keyed by the issuer (heketi's restuser)
+    /// of the tokens it sends
+    pub classes: BTreeMap<String, ClassConfig>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClassConfig {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    #[serde(deserialize_with = "deserialize_opt_mode")]
+    pub mode: Option<mode_t>,
+    pub setgid: Option<bool>,
+    pub gid_min: Option<u32>,
+    pub gid_max: Option<u32>,
+    pub selinux_label: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    /// Size in GB used when a create request asks for 0
+    pub default_size: u64,
+    /// Largest size in GB a single volume may request.  0 means unlimited
+    pub max_size: u64,
+    /// Percent of headroom above the current usage a volume must keep when
+    /// it's shrunk
+    pub shrink_margin: u64,
+    /// Percent of the gluster volume's size that the quotas of every volume
+    /// may add up to.  0 means unlimited
+    pub overcommit: u64,
+    /// Set and read limits through gfapi xattrs rather than the gluster cli.
+    /// Those limits never reach glusterd's quota.conf, so `gluster volume
+    /// quota list` doesn't show them and glusterd won't restore them
+    pub native: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodesConfig {
+    /// Zone reported for nodes that aren't listed in `zones`
+    pub default_zone: u32,
+    /// Zone of each node keyed by its gluster uuid or any of its hostnames
+    pub zones: BTreeMap<String, u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockConfig {
+    /// Seconds to wait for another request or instance to finish with a
+    /// volume
+    pub timeout: u64,
+    /// Seconds after which a lock that's still held is broken as stuck
+    pub stale_after: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeaderConfig {
+    /// Stand for leader.  Instances with this off still know the leader
+    pub enabled: bool,
+    /// Seconds a leader's lease lasts without a heartbeat
+    pub lease: u64,
+    /// Seconds between heartbeats
+    pub heartbeat: u64,
+    /// Where other instances send leader only requests while this instance
+    /// leads, ie "http://gluster-1.example.com:8080"
+    pub url: Option<String>,
+    /// Seconds between the leader's runs of the cluster wide clean up.  0
+    /// turns it off
+    pub jobs_interval: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Rocket log level: off, critical, normal or debug.  Changing it needs
+    /// a restart
+    pub level: String,
+    /// Log file for the gfapi client
+    pub gluster_log: PathBuf,
+    /// gfapi log level: none, emergency, alert, critical, error, warning,
+    /// notice, info, debug or trace
+    pub gluster_level: String,
+}
+
+impl Default for GlusterConfig {
+    fn default() -> Self {
+        GlusterConfig { volume: "".into(),
+                        host: "localhost".into(),
+                        port: 24007,
+                        state_dir: PathBuf::from("/var/lib/glusterd"),
+                        cluster_id: None }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self { ServerConfig { address: "0.0.0.0".into(), port: 8080 } }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig { secret: None,
+                     secret_file: None,
+                     keys: vec![],
+                     jwks_file: None,
+                     admin_issuers: vec!["admin".into()],
+                     leeway: 60,
+                     key_set: KeySet::default() }
+    }
+}
+
+impl Default for VolumeConfig {
+    fn default() -> Self {
+        VolumeConfig { default_uid: 0,
+                       default_gid: None,
+                       default_mode: 0o570,
+                       setgid: false,
+                       gid_min: None,
+                       gid_max: None,
+                       selinux_label: None,
+                       classes: BTreeMap::new() }
+    }
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig { default_size: 1,
+                      max_size: 0,
+                      shrink_margin: 10,
+                      overcommit: 0,
+                      native: false }
+    }
+}
+
+impl Default for NodesConfig {
+    fn default() -> Self { NodesConfig { default_zone: 1, zones: BTreeMap::new() } }
+}
+
+impl Default for LockConfig {
+    fn default() -> Self { LockConfig { timeout: 30, stale_after: 600 } }
+}
+
+impl Default for LeaderConfig {
+    fn default() -> Self {
+        LeaderConfig { enabled: true, lease: 30, heartbeat: 10, url: None, jobs_interval: 3600 }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig { level: "normal".into(),
+                    gluster_log: PathBuf::from("/var/log/piragua_gfapi"),
+                    gluster_level: "warning".into() }
+    }
+}
+
+/// A problem found while loading or validating the configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, String),
+    Parse(PathBuf, String),
+    Env(&'static str, String),
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(p, e) => write!(f, "unable to read {}: {}", p.display(), e),
+            ConfigError::Parse(p, e) => write!(f, "unable to parse {}: {}", p.display(), e),
+            ConfigError::Env(var, e) => write!(f, "invalid environment variable {}: {}", var, e),
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for p in problems {
+                    write!(f, "\n  - {}", p)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn deserialize_mode<'de, D>(deserializer: D) -> Result<mode_t, D::Error>
+    where D: Deserializer<'de>
+{
+    let s: String = serde::Deserialize::deserialize(deserializer)?;
+    parse_mode(&s).map_err(de::Error::custom)
+}
+
+fn deserialize_opt_mode<'de, D>(deserializer: D) -> Result<Option<mode_t>, D::Error>
+    where D: Deserializer<'de>
+{
+    let s: String = serde::Deserialize::deserialize(deserializer)?;
+    parse_mode(&s).map(Some).map_err(de::Error::custom)
+}
+
+// Check a gid range.  Both ends have to be set, or neither
+fn check_gid_range(name: &str, min: Option<u32>, max: Option<u32>, problems: &mut Vec<String>) {
+    match (min, max) {
+        (Some(0), _) => problems.push(format!("{}.gid_min must be at least 1", name)),
+        (Some(min), Some(max)) if min > max => {
+            problems.push(format!("{}.gid_min {} is larger than gid_max {}", name, min, max))
+        }
+        (Some(_), None) | (None, Some(_)) => {
+            problems.push(format!("{}.gid_min and gid_max must be set together", name))
+        }
+        _ => {}
+    }
+}
+
+/// Parse an octal permission string such as "0570" or "2770"
+pub fn parse_mode(s: &str) -> Result<mode_t, String> {
+    let mode = mode_t::from_str_radix(s, 8).map_err(|e| format!("mode {:?}: {}", s, e))?;
+    if mode > 0o7777 {
+        return Err(format!("mode {:?} is out of range", s));
+    }
+    Ok(mode)
+}
+
+// Read an environment variable, treating unset and empty the same way
+fn env_var(name: &str) -> Option<String> {
+    match env::var(name) {
+        Ok(ref s) if s.is_empty() => None,
+        Ok(s) => Some(s),
+        Err(_) => None,
+    }
+}
+
+fn env_parse<T: FromStr>(name: &'static str) -> Result<Option<T>, ConfigError>
+    where T::Err: fmt::Display
+{
+    match env_var(name) {
+        Some(s) => T::from_str(&s).map(Some).map_err(|e| ConfigError::Env(name, e.to_string())),
+        None => Ok(None),
+    }
+}
+
+impl Config {
+    /// Load the configuration file at `path` and apply environment overrides.
+    /// A missing file is only an error if `required` is set, otherwise the
+    /// defaults are used.
+    pub fn load(path: &Path, required: bool) -> Result<Config, ConfigError> {
+        let mut config = if path.exists() || required {
+            let s = fs::read_to_string(path).map_err(|e| {
+                                                ConfigError::Io(path.to_path_buf(), e.to_string())
+                                            })?;
+            Config::from_toml(&s).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?
+        } else {
+            Config::default()
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    pub fn from_toml(s: &str) -> Result<Config, String> {
+        toml::from_str(s).map_err(|e| e.to_string())
+    }
+
+    /// Environment variables take precedence over the config file.  The
+    /// names used by the original environment file are still honored.
+    pub fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(v) = env_var("PIRAGUA_VOLUME").or_else(|| env_var("GLUSTER_VOL")) {
+            self.gluster.volume = v;
+        }
+        if let Some(h) = env_var("PIRAGUA_GLUSTERD_HOST") {
+            self.gluster.host = h;
+        }
+        if let Some(p) = env_parse("PIRAGUA_GLUSTERD_PORT")? {
+            self.gluster.port = p;
+        }
+        if let Some(d) = env_var("PIRAGUA_STATE_DIR") {
+            self.gluster.state_dir = PathBuf::from(d);
+        }
+        if let Some(a) = env_var("PIRAGUA_ADDRESS").or_else(|| env_var("ROCKET_ADDRESS")) {
+            self.server.address = a;
+        }
+        if let Some(p) = env_parse("PIRAGUA_PORT")? {
+            self.server.port = p;
+        } else if let Some(p) = env_parse("ROCKET_PORT")? {
+            self.server.port = p;
+        }
+        if let Some(s) = env_var("JWT_SECRET") {
+            self.auth.secret = Some(s);
+            self.auth.secret_file = None;
+        }
+        if let Some(l) = env_var("PIRAGUA_LOG").or_else(|| env_var("ROCKET_LOG")) {
+            self.log.level = l;
+        }
+        if let Some(l) = env_var("GLUSTER_LOG") {
+            self.log.gluster_log = PathBuf::from(l);
+        }
+        Ok(())
+    }
+
+    /// Check every setting and report all of the problems at once.  Returns
+    /// the decoded auth keys so the secrets are only read once
+    pub fn validate(&self) -> Result<KeySet, ConfigError> {
+        let mut problems: Vec<String> = vec![];
+
+        if self.gluster.volume.is_empty() {
+            problems.push("gluster.volume must be set".into());
+        } else if self.gluster.volume.chars().any(|c| {
+                                                 !(c.is_ascii_alphanumeric()
+                                                   || c == '-'
+                                                   || c == '_')
+                                             })
+        {
+            problems.push(format!("gluster.volume {:?} is not a valid gluster volume name",
+                                  self.gluster.volume));
+        }
+        if self.gluster.host.is_empty() {
+            problems.push("gluster.host must not be empty".into());
+        }
+        if self.gluster.port == 0 {
+            problems.push("gluster.port must not be 0".into());
+        }
+        if !self.gluster.state_dir.is_absolute() {
+            problems.push(format!("gluster.state_dir {} must be an absolute path",
+                                  self.gluster.state_dir.display()));
+        }
+        if let Some(ref id) = self.gluster.cluster_id {
+            if id.is_empty()
+               || id.len() > 64
+               || id.chars().any(|c| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+            {
+                problems.push(format!("gluster.cluster_id {:?} must be 1 to 64 letters, numbers, \
+                                       '-' or '_'",
+                                      id));
+            }
+        }
+        if self.server.address != "localhost" && IpAddr::from_str(&self.server.address).is_err() {
+            problems.push(format!("server.address {:?} is not an IP address", self.server.address));
+        }
+        if self.server.port == 0 {
+            problems.push("server.port must not be 0".into());
+        }
+        let key_set = match KeySet::load(&self.auth) {
+            Ok(key_set) => key_set,
+            Err(mut e) => {
+                problems.append(&mut e);
+                KeySet::default()
+            }
+        };
+        if self.volumes.default_mode & 0o700 == 0 {
+            problems.push("volumes.default_mode must give the owner some access".into());
+        }
+        check_gid_range("volumes", self.volumes.gid_min, self.volumes.gid_max, &mut problems);
+        if let Some(Err(e)) = self.volumes.selinux_label.as_ref().map(|l| check_label(l)) {
+            problems.push(format!("volumes.selinux_label: {}", e));
+        }
+        for (name, class) in &self.volumes.classes {
+            let name = format!("volumes.classes.{}", name);
+            if class.mode.map_or(false, |mode| mode & 0o700 == 0) {
+                problems.push(format!("{}.mode must give the owner some access", name));
+            }
+            check_gid_range(&name, class.gid_min, class.gid_max, &mut problems);
+            if let Some(Err(e)) = class.selinux_label.as_ref().map(|l| check_label(l)) {
+                problems.push(format!("{}.selinux_label: {}", name, e));
+            }
+        }
+        if self.quota.default_size == 0 {
+            problems.push("quota.default_size must be at least 1GB".into());
+        }
+        if self.quota.max_size != 0 && self.quota.max_size < self.quota.default_size {
+            problems.push(format!("quota.max_size {}GB is smaller than quota.default_size {}GB",
+                                  self.quota.max_size, self.quota.default_size));
+        }
+        if self.quota.shrink_margin > 100 {
+            problems.push(format!("quota.shrink_margin {}% must be at most 100%",
+                                  self.quota.shrink_margin));
+        }
+        if self.nodes.default_zone == 0 {
+            problems.push("nodes.default_zone must be at least 1".into());
+        }
+        for (node, zone) in &self.nodes.zones {
+            if *zone == 0 {
+                problems.push(format!("nodes.zones {:?} must be at least 1", node));
+            }
+        }
+        if self.leader.heartbeat == 0 || self.leader.heartbeat >= self.leader.lease {
+            problems.push(format!("leader.heartbeat {}s must be at least 1s and shorter than \
+                                   leader.lease {}s",
+                                  self.leader.heartbeat, self.leader.lease));
+        }
+        if let Some(ref url) = self.leader.url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                problems.push(format!("leader.url {:?} must be an http or https url", url));
+            }
+        }
+        if rocket::config::LoggingLevel::from_str(&self.log.level).is_err() {
+            problems.push(format!("log.level {:?} must be one of off, critical, normal or debug",
+                                  self.log.level));
+        }
+        if let Err(e) = self.log.gluster_log_level() {
+            problems.push(e);
+        }
+
+        if problems.is_empty() {
+            Ok(key_set)
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
+
+/// Where the configuration came from, so it can be loaded again on reload
+#[derive(Clone, Debug)]
+pub struct ConfigSource {
+    pub path: PathBuf,
+    /// Fail if the file is missing instead of using the defaults
+    pub required: bool,
+    /// --volume from the command line
+    pub volume: Option<String>,
+}
+
+impl ConfigSource {
+    /// Load, override, validate and read the secret in one go
+    pub fn load(&self) -> Result<Config, ConfigError> {
+        let mut config = Config::load(&self.path, self.required)?;
+        if let Some(ref volume) = self.volume {
+            config.gluster.volume = volume.clone();
+        }
+        config.auth.key_set = config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Settings only read at startup.  A reload keeps their running values
+pub const STARTUP_ONLY: &str = "gluster, server and log.level settings";
+
+/// The result of a successful reload
+pub struct Reload {
+    pub old: Arc<Config>,
+    pub new: Arc<Config>,
+    /// Human readable description of every setting that changed
+    pub changes: Vec<String>,
+    /// Settings that changed in the file but keep their running values
+    /// until a restart
+    pub not_applied: Vec<String>,
+}
+
+/// The running configuration.  Requests take a snapshot with `current()` so
+/// a reload never changes settings underneath a request that is in flight.
+pub struct LiveConfig {
+    source: ConfigSource,
+    current: RwLock<Arc<Config>>,
+}
+
+impl LiveConfig {
+    pub fn new(source: ConfigSource, config: Config) -> LiveConfig {
+        LiveConfig { source, current: RwLock::new(Arc::new(config)) }
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        // A poisoned lock still holds a valid config, it was only ever swapped whole
+        match self.current.read() {
+            Ok(c) => c.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Re-read the config file and secret.  Settings that are baked into the
+    /// gfapi connection, the listening socket or Rocket's logger, which keeps
+    /// the level it was launched with, keep their old values and are
+    /// reported as not applied.
+    pub fn reload(&self) -> Result<Reload, ConfigError> {
+        let mut new = self.source.load()?;
+        let old = self.current();
+        let changes = diff(&old, &new);
+        let mut not_applied = vec![];
+        if new.gluster != old.gluster {
+            not_applied.push("gluster settings require a restart".into());
+            new.gluster = old.gluster.clone();
+        }
+        if new.server != old.server {
+            not_applied.push("server settings require a restart".into());
+            new.server = old.server.clone();
+        }
+        if new.log.level != old.log.level {
+            not_applied.push(format!("log.level {:?} requires a restart, still logging at {:?}",
+                                     new.log.level, old.log.level));
+            new.log.level = old.log.level.clone();
+        }
+        let new = Arc::new(new);
+        match self.current.write() {
+            Ok(mut c) => *c = new.clone(),
+            Err(poisoned) => *poisoned.into_inner() = new.clone(),
+        }
+        Ok(Reload { old, new, changes, not_applied })
+    }
+}
+
+/// List the settings that differ between two configs.  Secrets are never
+/// printed, only whether they changed.
+pub fn diff(old: &Config, new: &Config) -> Vec<String> {
+    let mut changes = vec![];
+    macro_rules! compare {
+        ($($section:ident . $field:ident),*) => {
+            $(
+                if old.$section.$field != new.$section.$field {
+                    changes.push(format!("{}.{}: {:?} -> {:?}",
+                                         stringify!($section),
+                                         stringify!($field),
+                                         old.$section.$field,
+                                         new.$section.$field));
+                }
+            )*
+        };
+    }
+    compare!(gluster.volume,
+             gluster.host,
+             gluster.port,
+             gluster.state_dir,
+             gluster.cluster_id,
+             server.address,
+             server.port,
+             auth.admin_issuers,
+             auth.leeway,
+             volumes.default_uid,
+             volumes.default_gid,
+             volumes.setgid,
+             volumes.gid_min,
+             volumes.gid_max,
+             volumes.selinux_label,
+             quota.default_size,
+             quota.max_size,
+             quota.shrink_margin,
+             quota.overcommit,
+             quota.native,
+             nodes.default_zone,
+             nodes.zones,
+             lock.timeout,
+             lock.stale_after,
+             leader.enabled,
+             leader.lease,
+             leader.heartbeat,
+             leader.url,
+             leader.jobs_interval,
+             log.level,
+             log.gluster_log,
+             log.gluster_level);
+    if old.volumes.default_mode != new.volumes.default_mode {
+        changes.push(format!("volumes.default_mode: {:o} -> {:o}",
+                             old.volumes.default_mode, new.volumes.default_mode));
+    }
+    diff_classes(&old.volumes.classes, &new.volumes.classes, &mut changes);
+    changes.append(&mut old.auth.key_set.diff(&new.auth.key_set));
+    changes
+}
+
+// Modes are shown in octal as they're written in the config file
+fn octal(mode: Option<mode_t>) -> String { mode.map_or("None".into(), |m| format!("{:o}", m)) }
+
+fn diff_classes(old: &BTreeMap<String, ClassConfig>,
+                new: &BTreeMap<String, ClassConfig>,
+                changes: &mut Vec<String>) {
+    let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    for name in names {
+        let (o, n) = match (old.get(name), new.get(name)) {
+            (Some(o), Some(n)) => (o, n),
+            (Some(_), None) => {
+                changes.push(format!("volumes.classes.{}: removed", name));
+                continue;
+            }
+            _ => {
+                changes.push(format!("volumes.classes.{}: added", name));
+                continue;
+            }
+        };
+        macro_rules! compare {
+            ($($field:ident),*) => {
+                $(
+                    if o.$field != n.$field {
+                        changes.push(format!("volumes.classes.{}.{}: {:?} -> {:?}",
+                                             name,
+                                             stringify!($field),
+                                             o.$field,
+                                             n.$field));
+                    }
+                )*
+            };
+        }
+        compare!(uid, gid, setgid, gid_min, gid_max, selinux_label);
+        if o.mode != n.mode {
+            changes.push(format!("volumes.classes.{}.mode: {} -> {}",
+                                 name,
+                                 octal(o.mode),
+                                 octal(n.mode)));
+        }
+    }
+}
+
+impl NodesConfig {
+    /// The zone of the node with this uuid and hostnames
+    pub fn zone(&self, uuid: &str, hostnames: &[String]) -> u32 {
+        self.zones
+            .get(uuid)
+            .or_else(|| hostnames.iter().filter_map(|h| self.zones.get(h)).next())
+            .cloned()
+            .unwrap_or(self.default_zone)
+    }
+}
+
+impl LogConfig {
+    pub fn gluster_log_level(&self) -> Result<gfapi_sys::gluster::GlusterLogLevel, String> {
+        use gfapi_sys::gluster::GlusterLogLevel;
+        match self.gluster_level.to_lowercase().as_ref() {
+            "none" => Ok(GlusterLogLevel::None),
+            "emergency" => Ok(GlusterLogLevel::Emerg),
+            "alert" => Ok(GlusterLogLevel::Alert),
+            "critical" => Ok(GlusterLogLevel::Critical),
+            "error" => Ok(GlusterLogLevel::Error),
+            "warning" => Ok(GlusterLogLevel::Warning),
+            "notice" => Ok(GlusterLogLevel::Notice),
+            "info" => Ok(GlusterLogLevel::Info),
+            "debug" => Ok(GlusterLogLevel::Debug),
+            "trace" => Ok(GlusterLogLevel::Trace),
+            other => Err(format!("log.gluster_level {:?} is not a gluster log level", other)),
+        }
+    }
+}
+
+#[test]
+fn test_parse_config() {
+    let config = Config::from_toml(
+                                   r#"
+[gluster]
+volume = "gv0"
+state_dir = "/tmp/glusterd"
+
+[auth]
+secret = "c3VwZXJfc2VjcmV0"
+
+[volumes]
+default_mode = "2770"
+gid_min = 2000
+gid_max = 2999
+
+[volumes.classes.openshift]
+uid = 1000
+mode = "0770"
+setgid = true
+
+[nodes.zones]
+"gluster-2.example.com" = 2
+"#,
+    ).unwrap();
+    assert_eq!(config.gluster.volume, "gv0");
+    assert_eq!(config.gluster.port, 24007);
+    assert_eq!(config.gluster.state_dir, PathBuf::from("/tmp/glusterd"));
+    assert_eq!(config.volumes.default_mode, 0o2770);
+    assert_eq!(config.volumes.classes["openshift"].mode, Some(0o770));
+    assert_eq!(config.volumes.classes["openshift"].gid, None);
+    assert_eq!(config.nodes.zone("", &["10.0.0.2".into(), "gluster-2.example.com".into()]), 2);
+    assert_eq!(config.nodes.zone("", &["10.0.0.3".into()]), 1);
+    assert!(config.validate().is_ok());
+
+    assert!(Config::from_toml("[gluster]\nvolumes = \"gv0\"").is_err());
+    assert!(Config::from_toml("[volumes]\ndefault_mode = \"0999\"").is_err());
+    let mut config = config;
+    config.volumes.gid_max = None;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_diff_config() {
+    let old = Config::default();
+    let mut new = Config::default();
+    assert!(diff(&old, &new).is_empty());
+
+    new.quota.default_size = 5;
+    new.volumes.default_mode = 0o2770;
+    new.auth.secret = Some("cm90YXRlZA==".into());
+    new.auth.key_set = KeySet::load(&new.auth).unwrap();
+    assert_eq!(diff(&old, &new),
+               vec!["quota.default_size: 1 -> 5".to_string(),
+                    "volumes.default_mode: 570 -> 2770".to_string(),
+                    "auth key \"default\": added".to_string()]);
+
+    let mut old = new.clone();
+    old.volumes
+       .classes
+       .insert("openshift".into(), ClassConfig { mode: Some(0o770), ..Default::default() });
+    let mut new = old.clone();
+    new.volumes.classes.get_mut("openshift").unwrap().mode = Some(0o2770);
+    new.volumes.classes.insert("kube".into(), ClassConfig::default());
+    assert_eq!(diff(&old, &new),
+               vec!["volumes.classes.kube: added".to_string(),
+                    "volumes.classes.openshift.mode: 770 -> 2770".to_string()]);
+}
+
+#[test]
+fn test_validate_config() {
+    let mut config = Config::default();
+    config.server.address = "not an ip".into();
+    config.log.level = "loud".into();
+    match config.validate() {
+        Err(ConfigError::Invalid(problems)) => {
+            assert_eq!(problems.len(), 4);
+            assert!(problems[0].contains("gluster.volume"));
+        }
+        other => panic!("expected validation to fail: {:?}", other),
+    }
+}
+
+#[test]
+fn test_reload_keeps_log_level() {
+    let path = std::env::temp_dir().join(format!("piragua-reload-{}.toml", std::process::id()));
+    let write = |level: &str| {
+        std::fs::write(&path,
+                       format!("[gluster]\nvolume = \"gv0\"\n[auth]\nsecret = \
+                                \"c3VwZXJfc2VjcmV0\"\n[quota]\ndefault_size = 2\n[log]\n\
+                                level = \"{}\"\n",
+                               level)).unwrap()
+    };
+    write("normal");
+    let source = ConfigSource { path: path.clone(), required: true, volume: None };
+    let live = LiveConfig::new(source.clone(), source.load().unwrap());
+
+    write("debug");
+    let reload = live.reload();
+    std::fs::remove_file(&path).unwrap();
+    let reload = reload.unwrap();
+    assert_eq!(reload.new.log.level, "normal");
+    assert_eq!(live.current().log.level, "normal");
+    let not_applied = "log.level \"debug\" requires a restart, still logging at \"normal\"";
+    assert_eq!(reload.not_applied, vec![not_applied.to_string()]);
+}
@@ -0,0 +1,198 @@
+//! The errors every route and catcher answers with.
+//!
+//! Each error is sent as JSON with a machine readable `code`, a `message` for
+//! humans and the `request_id` that is also returned in the `X-Request-Id`
+//! header and printed in the logs so a failure can be found again.
+use std::{fmt, io};
+
+use gfapi_sys::gluster::GlusterError as GfapiError;
+use rocket::{fairing::AdHoc,
+             http::Status,
+             response::{self, Responder, Response},
+             Request};
+use rocket_contrib::json::Json;
+use uuid::Uuid;
+
+use crate::auth::AuthError;
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ApiError {
+    /// The volume, node or device doesn't exist
+    NotFound(String),
+    /// The request clashes with something that already exists
+    Conflict(String),
+    InvalidInput(String),
+    /// The volume or a quota is full
+    NoSpace(String),
+    /// gluster or glusterd couldn't be reached or failed
+    BackendUnavailable(String),
+    Auth(AuthError),
+    /// Something we didn't expect.  These are bugs or misconfiguration
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> Status {
+        match *self {
+            ApiError::NotFound(_) => Status::NotFound,
+            ApiError::Conflict(_) => Status::Conflict,
+            ApiError::InvalidInput(_) => Status::BadRequest,
+            ApiError::NoSpace(_) => Status::InsufficientStorage,
+            ApiError::BackendUnavailable(_) => Status::ServiceUnavailable,
+            ApiError::Auth(ref e) => e.status(),
+            ApiError::Internal(_) => Status::InternalServerError,
+        }
+    }
+
+    /// Machine readable name of the error
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::InvalidInput(_) => "invalid_input",
+            ApiError::NoSpace(_) => "no_space",
+            ApiError::BackendUnavailable(_) => "backend_unavailable",
+            ApiError::Auth(ref e) => e.code(),
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ApiError::NotFound(ref m)
+            | ApiError::Conflict(ref m)
+            | ApiError::InvalidInput(ref m)
+            | ApiError::NoSpace(ref m)
+            | ApiError::BackendUnavailable(ref m)
+            | ApiError::Internal(ref m) => write!(f, "{}", m),
+            ApiError::Auth(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// Pick the error from an errno
+fn from_errno(errno: i32, message: String) -> ApiError {
+    match errno {
+        // ENODATA is a missing xattr
+        libc::ENOENT | libc::ENOTDIR | libc::ENODATA => ApiError::NotFound(message),
+        libc::EEXIST | libc::ENOTEMPTY => ApiError::Conflict(message),
+        libc::ENOSPC | libc::EDQUOT => ApiError::NoSpace(message),
+        // Client input is validated before it reaches gluster, so EINVAL
+        // here is a bad xattr or layout on the server's side
+        libc::EINVAL | libc::ENAMETOOLONG | libc::EACCES | libc::EPERM => {
+            ApiError::Internal(message)
+        }
+        _ => ApiError::BackendUnavailable(message),
+    }
+}
+
+// gfapi errors only carry the message of the io::Error they were made from,
+// which ends with "(os error N)"
+pub fn parse_errno(message: &str) -> Option<i32> {
+    let start = message.rfind("(os error ")? + "(os error ".len();
+    message[start..].trim_end_matches(')').parse().ok()
+}
+
+impl From<GfapiError> for ApiError {
+    fn from(e: GfapiError) -> ApiError {
+        let message = e.to_string();
+        match parse_errno(&message) {
+            Some(errno) => from_errno(errno, message),
+            None => ApiError::BackendUnavailable(message),
+        }
+    }
+}
+
+impl From<gluster::GlusterError> for ApiError {
+    // The gluster cli failed or glusterd isn't answering
+    fn from(e: gluster::GlusterError) -> ApiError { ApiError::BackendUnavailable(e.to_string()) }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> ApiError {
+        match e.raw_os_error() {
+            Some(errno) => from_errno(errno, e.to_string()),
+            None if e.kind() == io::ErrorKind::NotFound => ApiError::NotFound(e.to_string()),
+            None => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> ApiError { ApiError::Internal(e.to_string()) }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(e: AuthError) -> ApiError { ApiError::Auth(e) }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    request_id: String,
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, req: &Request<'_>) -> response::Result<'r> {
+        let body =
+            ErrorBody { code: self.code(), message: self.to_string(), request_id: request_id(req) };
+        println!("request {} {} {} failed: {}: {}",
+                 body.request_id,
+                 req.method(),
+                 req.uri(),
+                 body.code,
+                 body.message);
+        let mut response = Response::build_from(Json(&body).respond_to(req)?);
+        response.status(self.status());
+        if let ApiError::Auth(ref e) = self {
+            if e.status() == Status::Unauthorized {
+                // Quotes would break the header so the description drops them
+                let description = e.to_string().replace('"', "'");
+                response.raw_header("WWW-Authenticate",
+                                    format!("Bearer error=\"{}\", error_description=\"{}\"",
+                                            e.code(),
+                                            description));
+            }
+        }
+        response.ok()
+    }
+}
+
+struct RequestId(String);
+
+/// The id of this request.  Clients and proxies may supply one with
+/// `X-Request-Id`, otherwise a new one is made up.
+pub fn request_id(req: &Request<'_>) -> String {
+    req.local_cache(|| {
+           let id = req.headers()
+                       .get_one("X-Request-Id")
+                       .filter(|id| !id.is_empty() && id.len() <= 128)
+                       .map(|id| id.to_string());
+           RequestId(id.unwrap_or_else(|| Uuid::new_v4().to_hyphenated().to_string()))
+       })
+       .0
+       .clone()
+}
+
+/// Echo the request id back on every response
+pub fn request_id_fairing() -> AdHoc {
+    AdHoc::on_response("Request ID", |req, res| {
+        res.set_raw_header("X-Request-Id", request_id(req));
+    })
+}
+
+#[test]
+fn test_gfapi_errors() {
+    let err = |msg: &str| ApiError::from(GfapiError::new(msg.into())).code();
+    assert_eq!(err("No such file or directory (os error 2)"), "not_found");
+    assert_eq!(err("File exists (os error 17)"), "conflict");
+    assert_eq!(err("No space left on device (os error 28)"), "no_space");
+    assert_eq!(err("Invalid argument (os error 22)"), "internal_error");
+    assert_eq!(err("Transport endpoint is not connected (os error 107)"), "backend_unavailable");
+    assert_eq!(err("glfs_init failed"), "backend_unavailable");
+}
@@ -0,0 +1,288 @@
+//! Unique gids for volumes.
+//!
+//! Every gid a volume has is claimed by a directory named after it in
+//! .piragua/gids on the gluster volume, holding an empty file named after
+//! each volume that has the gid.  Making the directory is atomic across every
+//! piragua instance so an allocated gid only ever goes to one volume, and a
+//! claim has nothing to half write.  A gid chosen by a request or the config
+//! may be shared and stays claimed until the last volume with it is deleted.
+//! Claims outlive restarts.
+use std::{collections::BTreeSet,
+          path::{Path, PathBuf},
+          str::FromStr,
+          time::{SystemTime, UNIX_EPOCH}};
+
+use gfapi_sys::gluster::Gluster;
+use libc::{O_CREAT, O_WRONLY, S_IRWXU};
+use uuid::Uuid;
+
+use crate::{error::{ApiError, ApiResult},
+            volume::{volume_ids, VolumeId, STATE_DIR}};
+
+/// How long a claim may go without its volume before it's taken for one left
+/// by an instance that died while creating the volume, in seconds
+const CLAIM_GRACE: i64 = 600;
+
+fn gids_dir() -> PathBuf { Path::new(STATE_DIR).join("gids") }
+
+fn claim_path(gid: u32) -> PathBuf { gids_dir().join(gid.to_string()) }
+
+fn owner_path(gid: u32, id: &VolumeId) -> PathBuf { claim_path(gid).join(id.as_str()) }
+
+// Treat an error as success if it's the one we expect
+fn allow(result: ApiResult<()>, expected: fn(&ApiError) -> bool) -> ApiResult<()> {
+    match result {
+        Err(ref e) if expected(e) => Ok(()),
+        result => result,
+    }
+}
+
+fn is_conflict(e: &ApiError) -> bool {
+    match *e {
+        ApiError::Conflict(_) => true,
+        _ => false,
+    }
+}
+
+fn is_not_found(e: &ApiError) -> bool {
+    match *e {
+        ApiError::NotFound(_) => true,
+        _ => false,
+    }
+}
+
+/// Claim a gid for a volume.  An `exclusive` claim is false if the gid
+/// already belongs to any volume, otherwise the volume is added to them
+fn claim(gluster: &Gluster, gid: u32, id: &VolumeId, exclusive: bool) -> ApiResult<bool> {
+    loop {
+        match gluster.mkdir(&claim_path(gid), S_IRWXU).map_err(ApiError::from) {
+            Ok(()) => {}
+            Err(ApiError::Conflict(_)) if !exclusive => {}
+            Err(ApiError::Conflict(_)) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        match gluster.create(&owner_path(gid, id), O_WRONLY | O_CREAT, 0o644)
+                     .map_err(ApiError::from)
+        {
+            Ok(_) => return Ok(true),
+            // Its last volume released it in between, claim it again
+            Err(ApiError::NotFound(_)) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// The volumes a gid is claimed by
+fn owners(gluster: &Gluster, gid: u32) -> ApiResult<Vec<String>> {
+    let dir = match gluster.opendir(&claim_path(gid)) {
+        Ok(dir) => dir,
+        Err(e) => return allow(Err(ApiError::from(e)), is_not_found).map(|_| vec![]),
+    };
+    let mut owners = vec![];
+    for dir_entry in dir {
+        match dir_entry?.path.to_str() {
+            Some(".") | Some("..") | None => {}
+            Some(owner) => owners.push(owner.to_string()),
+        }
+    }
+    Ok(owners)
+}
+
+// Make the gids directory the first time it's needed.  Volumes made before
+// gids were claimed keep theirs: their claims are written into a directory
+// with a temporary name that's then moved into place.  If another instance
+// got there first its directory is just as good.
+fn ensure_dir(gluster: &Gluster) -> ApiResult<()> {
+    if gluster.exists(&gids_dir())? {
+        return Ok(());
+    }
+    allow(gluster.mkdir(Path::new(STATE_DIR), S_IRWXU).map_err(ApiError::from), is_conflict)?;
+    let tmp = Path::new(STATE_DIR).join(format!("gids.{}", Uuid::new_v4()));
+    gluster.mkdir(&tmp, S_IRWXU)?;
+    let mut claimed = 0;
+    for id in volume_ids(gluster)? {
+        let gid = match gluster.stat(Path::new(id.as_str())) {
+            Ok(stat) => stat.st_gid,
+            Err(_) => continue,
+        };
+        if !claimable(gid) {
+            continue;
+        }
+        let dir = tmp.join(gid.to_string());
+        allow(gluster.mkdir(&dir, S_IRWXU).map_err(ApiError::from), is_conflict)?;
+        if gluster.create(&dir.join(id.as_str()), O_WRONLY | O_CREAT, 0o644).is_ok() {
+            claimed += 1;
+        }
+    }
+    match gluster.rename(&tmp, &gids_dir()).map_err(ApiError::from) {
+        Ok(()) => {
+            println!("Claimed the gids of {} existing volumes", claimed);
+            Ok(())
+        }
+        Err(ApiError::Conflict(_)) => {
+            gluster.remove_dir_all(&tmp)?;
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Root's group is never claimed, as volumes given it aren't sharing a gid
+// anything else could allocate
+fn claimable(gid: u32) -> bool { gid != 0 }
+
+// Gids in the range that aren't claimed, lowest first
+fn unclaimed<'a>(claimed: &'a BTreeSet<u32>, min: u32, max: u32) -> impl Iterator<Item = u32> + 'a {
+    (min..=max).filter(move |gid| claimable(*gid) && !claimed.contains(gid))
+}
+
+/// Claim the lowest free gid in the range for a volume
+pub fn allocate(gluster: &Gluster, min: u32, max: u32, id: &VolumeId) -> ApiResult<u32> {
+    ensure_dir(gluster)?;
+    let mut claimed = BTreeSet::new();
+    for dir_entry in gluster.opendir(&gids_dir())? {
+        if let Some(Ok(gid)) = dir_entry?.path.to_str().map(|p| p.parse::<u32>()) {
+            claimed.insert(gid);
+        }
+    }
+    // Another instance may claim one between the listing and our claim
+    for gid in unclaimed(&claimed, min, max) {
+        if claim(gluster, gid, id, true)? {
+            return Ok(gid);
+        }
+    }
+    Err(ApiError::Conflict(format!("Every gid from {} to {} is in use", min, max)))
+}
+
+/// Record a gid chosen by the request or config so it's never allocated.
+/// Several volumes may be given the same gid this way; it's claimed until
+/// the last of them is deleted.
+pub fn reserve(gluster: &Gluster, gid: u32, id: &VolumeId) -> ApiResult<()> {
+    if !claimable(gid) {
+        return Ok(());
+    }
+    ensure_dir(gluster)?;
+    claim(gluster, gid, id, false).map(|_| ())
+}
+
+/// Give back the gid of a volume that's about to be deleted
+pub fn release(gluster: &Gluster, id: &VolumeId) -> ApiResult<()> {
+    let gid = match gluster.stat(Path::new(id.as_str())) {
+        Ok(stat) => stat.st_gid,
+        Err(e) => return allow(Err(ApiError::from(e)), is_not_found),
+    };
+    release_gid(gluster, gid, id)
+}
+
+// Free a gid that no volume has.  Fails harmlessly while any still do
+fn remove_claim(gluster: &Gluster, gid: u32) -> ApiResult<()> {
+    match gluster.rmdir(&claim_path(gid)).map_err(ApiError::from) {
+        Ok(()) => {
+            println!("Gid {} is free", gid);
+            Ok(())
+        }
+        Err(ApiError::Conflict(_)) | Err(ApiError::NotFound(_)) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Give back a gid if this volume holds it.  The gid is free once no volume
+/// holds it
+pub fn release_gid(gluster: &Gluster, gid: u32, id: &VolumeId) -> ApiResult<()> {
+    if !claimable(gid) {
+        return Ok(());
+    }
+    match gluster.unlink(&owner_path(gid, id)).map_err(ApiError::from) {
+        Ok(()) => println!("Releasing gid {} of {}", gid, id),
+        Err(ApiError::NotFound(_)) => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    remove_claim(gluster, gid)
+}
+
+// Whether a claim without its volume was made long enough ago that whoever
+// made it is gone.  A volume's gid is claimed before its directory is made
+fn abandoned(mtime: i64, now: i64) -> bool { now - mtime > CLAIM_GRACE }
+
+// When a claim was made, if it's still there
+fn claimed_at(gluster: &Gluster, path: &Path) -> Option<i64> {
+    gluster.stat(path).ok().map(|stat| stat.st_mtime as i64)
+}
+
+/// Give back the gids of volumes that no longer exist, and claims left with
+/// no volume by an instance that died while claiming.  Returns each gid and
+/// the volume it was released from, or that would be with `dry_run`
+pub fn release_orphans(gluster: &Gluster,
+                       dry_run: bool)
+                       -> ApiResult<Vec<(u32, Option<VolumeId>)>> {
+    if !gluster.exists(&gids_dir())? {
+        return Ok(vec![]);
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as i64;
+    let mut released = vec![];
+    for dir_entry in gluster.opendir(&gids_dir())? {
+        let gid = match dir_entry?.path.to_str().map(|p| p.parse::<u32>()) {
+            Some(Ok(gid)) => gid,
+            _ => continue,
+        };
+        // Earlier versions claimed gid 0 and never released it
+        if !claimable(gid) {
+            if !dry_run {
+                gluster.remove_dir_all(&claim_path(gid))?;
+            }
+            released.push((gid, None));
+            continue;
+        }
+        let owners = owners(gluster, gid)?;
+        if owners.is_empty() {
+            if claimed_at(gluster, &claim_path(gid)).map_or(false, |t| abandoned(t, now)) {
+                if !dry_run {
+                    remove_claim(gluster, gid)?;
+                }
+                released.push((gid, None));
+            }
+            continue;
+        }
+        for owner in owners {
+            let id = match VolumeId::from_str(&owner) {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+            if gluster.exists(Path::new(id.as_str()))?
+               || !claimed_at(gluster, &owner_path(gid, &id)).map_or(false, |t| abandoned(t, now))
+            {
+                continue;
+            }
+            if !dry_run {
+                release_gid(gluster, gid, &id)?;
+            }
+            released.push((gid, Some(id)));
+        }
+    }
+    Ok(released)
+}
+
+#[test]
+fn test_unclaimed() {
+    let claimed = vec![2000, 2001, 2003].into_iter().collect();
+    assert_eq!(unclaimed(&claimed, 2000, 2999).next(), Some(2002));
+    assert_eq!(unclaimed(&claimed, 2000, 2004).collect::<Vec<_>>(), vec![2002, 2004]);
+    assert_eq!(unclaimed(&claimed, 2000, 2001).next(), None);
+    assert_eq!(unclaimed(&BTreeSet::new(), u32::max_value(), u32::max_value()).count(), 1);
+    assert_eq!(unclaimed(&BTreeSet::new(), 0, 1).collect::<Vec<_>>(), vec![1]);
+}
+
+#[test]
+fn test_root_gid_unclaimed() {
+    // reserve and release_gid both skip it, so neither leaves a claim behind
+    assert!(!claimable(0));
+    assert!(claimable(1));
+    assert!(claimable(2000));
+}
+
+#[test]
+fn test_abandoned() {
+    assert!(!abandoned(1_000, 1_000));
+    assert!(!abandoned(1_000, 1_000 + CLAIM_GRACE));
+    assert!(abandoned(1_000, 1_001 + CLAIM_GRACE));
+}
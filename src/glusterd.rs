@@ -0,0 +1,197 @@
+//! Readers for the state glusterd keeps under its working directory,
+//! normally /var/lib/glusterd.
+use std::{collections::HashMap,
+          fs::{self, File},
+          io::{BufRead, BufReader, Error, ErrorKind, Result as IOResult},
+          path::{Path, PathBuf},
+          str::FromStr};
+
+use uuid::Uuid;
+
+// List the peer uuids but not the local one.  Use get_local_uuid for that
+pub fn get_peer_uuids(state_dir: &Path) -> IOResult<Vec<Uuid>> {
+    let mut uuids: Vec<Uuid> = Vec::new();
+    for entry in fs::read_dir(state_dir.join("peers"))? {
+        let entry = entry?;
+        let u = Uuid::from_str(&entry.file_name().to_string_lossy()).map_err(|e| {
+                                                                        Error::new(ErrorKind::Other,
+                                                                                   e.to_string())
+                                                                    })?;
+        uuids.push(u);
+    }
+    Ok(uuids)
+}
+
+// Get the local uuid for this glusterd
+pub fn get_local_uuid(state_dir: &Path) -> IOResult<Option<Uuid>> {
+    let f = File::open(state_dir.join("glusterd.info"))?;
+    let f = BufReader::new(f);
+    for line in f.lines() {
+        let l = line?;
+        if l.starts_with("UUID") {
+            let l = l.replace("UUID=", "");
+            let guid = Uuid::from_str(&l).map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+            return Ok(Some(guid));
+        }
+    }
+    Ok(None)
+}
+
+/// What a peer file in peers/<uuid> says about the peer
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerInfo {
+    pub uuid: Option<Uuid>,
+    /// glusterd's friend state machine state.  3 is "Peer in Cluster"
+    pub state: Option<u32>,
+    /// Every name or address the peer was probed with, hostname1 first
+    pub hostnames: Vec<String>,
+}
+
+impl PeerInfo {
+    /// heketi's node state for this peer
+    pub fn node_state(&self) -> &'static str {
+        match self.state {
+            // Befriended
+            Some(3) => "online",
+            // Rejected
+            Some(6) => "failed",
+            _ => "offline",
+        }
+    }
+}
+
+fn parse_peer(data: &str) -> PeerInfo {
+    let mut peer = PeerInfo::default();
+    let mut hostnames: Vec<(u32, String)> = vec![];
+    for line in data.lines() {
+        let mut parts = line.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(k), Some(v)) if !v.trim().is_empty() => (k, v.trim()),
+            _ => continue,
+        };
+        if key == "uuid" {
+            peer.uuid = Uuid::from_str(value).ok();
+        } else if key == "state" {
+            peer.state = value.parse().ok();
+        } else if key.starts_with("hostname") {
+            if let Ok(n) = key["hostname".len()..].parse() {
+                hostnames.push((n, value.to_string()));
+            }
+        }
+    }
+    hostnames.sort();
+    for (_, hostname) in hostnames {
+        if !peer.hostnames.contains(&hostname) {
+            peer.hostnames.push(hostname);
+        }
+    }
+    peer
+}
+
+// Get what glusterd knows about a peer
+pub fn get_peer_info(state_dir: &Path, uuid: &Uuid) -> IOResult<PeerInfo> {
+    let data = fs::read_to_string(state_dir.join("peers").join(uuid.to_hyphenated().to_string()))?;
+    Ok(parse_peer(&data))
+}
+
+pub fn get_gluster_vol(state_dir: &Path, vol_id: &str) -> IOResult<HashMap<String, String>> {
+    let vol_file = File::open(state_dir.join("vols").join(vol_id).join("info"))?;
+    let mut vol_data = HashMap::new();
+    let f = BufReader::new(vol_file);
+    for line in f.lines() {
+        let l = line?;
+        let parts: Vec<&str> = l.split('=').collect();
+        if parts.len() != 2 {
+            // Skip broken data
+            continue;
+        }
+        vol_data.insert(parts[0].to_string(), parts[1].to_string());
+    }
+    Ok(vol_data)
+}
+
+// The volume-id glusterd gave the gluster volume when it was created
+pub fn get_volume_id(state_dir: &Path, vol_name: &str) -> IOResult<Option<Uuid>> {
+    let vol_data = get_gluster_vol(state_dir, vol_name)?;
+    Ok(vol_data.get("volume-id").and_then(|id| Uuid::from_str(id).ok()))
+}
+
+/// A brick of a gluster volume as glusterd stores it in
+/// vols/<volume>/bricks/<host>:<path>
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BrickInfo {
+    pub hostname: String,
+    pub path: PathBuf,
+    /// Block device under the brick, if glusterd recorded one
+    pub device_path: Option<PathBuf>,
+    /// Where the brick's filesystem is mounted, relative to the brick path
+    pub mount_dir: Option<PathBuf>,
+    pub brick_id: Option<String>,
+}
+
+fn parse_brick(data: &str) -> BrickInfo {
+    let mut brick = BrickInfo::default();
+    for line in data.lines() {
+        let mut parts = line.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(k), Some(v)) if !v.is_empty() => (k, v),
+            _ => continue,
+        };
+        match key {
+            "hostname" => brick.hostname = value.to_string(),
+            "path" => brick.path = PathBuf::from(value),
+            "device_path" => brick.device_path = Some(PathBuf::from(value)),
+            "mount_dir" => brick.mount_dir = Some(PathBuf::from(value)),
+            "brick-id" => brick.brick_id = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    brick
+}
+
+/// Every brick of the volume, sorted by host and path
+pub fn get_bricks(state_dir: &Path, vol: &str) -> IOResult<Vec<BrickInfo>> {
+    let mut bricks = vec![];
+    for entry in fs::read_dir(state_dir.join("vols").join(vol).join("bricks"))? {
+        let entry = entry?;
+        let brick = parse_brick(&fs::read_to_string(entry.path())?);
+        if brick.hostname.is_empty() || brick.path.as_os_str().is_empty() {
+            // Skip broken data
+            continue;
+        }
+        bricks.push(brick);
+    }
+    bricks.sort_by(|a, b| (&a.hostname, &a.path).cmp(&(&b.hostname, &b.path)));
+    Ok(bricks)
+}
+
+#[test]
+fn test_parse_brick() {
+    let brick = parse_brick("hostname=gluster-1.example.com\n\
+                             path=/bricks/b1/brick\n\
+                             real_path=/bricks/b1/brick\n\
+                             listen-port=49152\n\
+                             brick-id=vol-client-0\n\
+                             mount_dir=/brick\n\
+                             device_path=/dev/mapper/vg-b1\n\
+                             mnt-opts=rw,inode64,logbsize=256k\n");
+    assert_eq!(brick,
+               BrickInfo { hostname: "gluster-1.example.com".into(),
+                           path: PathBuf::from("/bricks/b1/brick"),
+                           device_path: Some(PathBuf::from("/dev/mapper/vg-b1")),
+                           mount_dir: Some(PathBuf::from("/brick")),
+                           brick_id: Some("vol-client-0".into()) });
+}
+
+#[test]
+fn test_parse_peer() {
+    let peer = parse_peer("uuid=5e0b3f4a-7a0c-4c55-9a53-0e7a9e4f6c11\n\
+                           state=3\n\
+                           hostname1=gluster-2.example.com\n\
+                           hostname3=gluster-2\n\
+                           hostname2=10.0.0.2\n");
+    assert_eq!(peer.hostnames, vec!["gluster-2.example.com", "10.0.0.2", "gluster-2"]);
+    assert_eq!(peer.node_state(), "online");
+    assert_eq!(parse_peer("state=6\nhostname1=10.0.0.3").node_state(), "failed");
+    assert_eq!(parse_peer("").node_state(), "offline");
+}
@@ -0,0 +1,279 @@
+//! Choosing the one piragua instance that runs work meant to happen once per
+//! cluster rather than once per server.
+//!
+//! The leader holds a lease in .piragua/leader on the gluster volume and
+//! renews it every leader.heartbeat seconds.  A lease that isn't renewed
+//! within leader.lease seconds can be taken by any instance.  The lease is
+//! only read and written under the "leader" lock so two instances can't both
+//! take over an expired one.  Instances compare expiry times with their own
+//! clocks so the servers need to keep them in sync.
+//!
+//! Every leader.jobs_interval the leader cleans up after deleted volumes:
+//! their stale quotas and gid claims.  Other instances skip it.
+use std::{path::{Path, PathBuf},
+          sync::{Arc, Mutex},
+          thread,
+          time::Duration};
+
+use chrono::Utc;
+use gfapi_sys::gluster::Gluster;
+use libc::{O_CREAT, O_RDONLY, O_TRUNC, O_WRONLY};
+use uuid::Uuid;
+
+use crate::{config::{LeaderConfig, LiveConfig, LockConfig},
+            error::{ApiError, ApiResult},
+            gids,
+            lock::Locks,
+            quota,
+            topology::local_hostname,
+            volume::STATE_DIR};
+
+fn lease_path() -> PathBuf { Path::new(STATE_DIR).join("leader") }
+
+/// The lease of the current leader
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Lease {
+    /// Made up each time an instance starts
+    pub instance: String,
+    pub host: String,
+    /// Where leader only requests can be sent, from leader.url
+    pub url: Option<String>,
+    /// Unix times it was last renewed and when it runs out
+    pub renewed: i64,
+    pub expires: i64,
+}
+
+/// What this instance knows about the leader
+pub struct Leadership {
+    instance: String,
+    host: String,
+    lease: Mutex<Option<Lease>>,
+}
+
+/// Whether `instance` may take or renew the lease at `now`
+fn can_take(current: Option<&Lease>, instance: &str, now: i64) -> bool {
+    current.map_or(true, |lease| lease.instance == instance || lease.expires < now)
+}
+
+fn read_lease(gluster: &Gluster) -> ApiResult<Option<Lease>> {
+    let file = match gluster.open(&lease_path(), O_RDONLY) {
+        Ok(file) => file,
+        Err(e) => match ApiError::from(e) {
+            ApiError::NotFound(_) => return Ok(None),
+            e => return Err(e),
+        },
+    };
+    let mut data = Vec::with_capacity(1024);
+    file.read(&mut data, 1024, 0)?;
+    match serde_json::from_slice(&data) {
+        Ok(lease) => Ok(Some(lease)),
+        // Its writer died part way through, the lease is up for grabs
+        Err(e) => {
+            println!("Ignoring the unreadable leader lease: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+fn write_lease(gluster: &Gluster, lease: &Lease) -> ApiResult<()> {
+    let file = gluster.create(&lease_path(), O_WRONLY | O_CREAT | O_TRUNC, 0o644)?;
+    file.write(&serde_json::to_vec(lease)?, 0)?;
+    Ok(())
+}
+
+impl Leadership {
+    pub fn new() -> Leadership {
+        Leadership { instance: Uuid::new_v4().to_hyphenated().to_string(),
+                     host: local_hostname().unwrap_or_else(|| "unknown".into()),
+                     lease: Mutex::new(None) }
+    }
+
+    /// This instance's id
+    pub fn instance(&self) -> &str { &self.instance }
+
+    /// The leader as of the last heartbeat, unless its lease ran out since
+    pub fn leader(&self) -> Option<Lease> {
+        let now = Utc::now().timestamp();
+        self.lease.lock().unwrap().clone().filter(|lease| lease.expires >= now)
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader().map_or(false, |lease| lease.instance == self.instance)
+    }
+
+    /// Renew our lease or take over one that ran out.  With leader.enabled
+    /// off this only finds out who the leader is.
+    pub fn heartbeat(&self,
+                     config: &LeaderConfig,
+                     lock: &LockConfig,
+                     gluster: &Gluster,
+                     locks: &Locks)
+                     -> ApiResult<()> {
+        let _lock = locks.lock(lock, gluster, "leader", "leader election")?;
+        let now = Utc::now().timestamp();
+        let current = read_lease(gluster)?;
+        let lease = if config.enabled && can_take(current.as_ref(), &self.instance, now) {
+            let lease = Lease { instance: self.instance.clone(),
+                                host: self.host.clone(),
+                                url: config.url.clone(),
+                                renewed: now,
+                                expires: now + config.lease as i64 };
+            write_lease(gluster, &lease)?;
+            if current.as_ref().map(|l| &l.instance) != Some(&self.instance) {
+                println!("This instance, {}, is now the leader", self.instance);
+            }
+            Some(lease)
+        } else {
+            current
+        };
+        *self.lease.lock().unwrap() = lease;
+        Ok(())
+    }
+
+    /// Check this instance may run a leader only job.  Otherwise the error
+    /// names the leader and its url, if it has one
+    pub fn require_leader(&self, job: &str) -> Result<(), (ApiError, Option<String>)> {
+        if self.is_leader() {
+            return Ok(());
+        }
+        match self.leader() {
+            Some(lease) => {
+                let msg =
+                    format!("Only the leader, {} on {}, runs {}", lease.instance, lease.host, job);
+                Err((ApiError::BackendUnavailable(msg), lease.url))
+            }
+            None => {
+                let msg = format!("There is no leader to run {} yet", job);
+                Err((ApiError::BackendUnavailable(msg), None))
+            }
+        }
+    }
+}
+
+/// The leader according to its lease, for tools that don't take part in the
+/// election
+pub fn current_leader(config: &LockConfig,
+                      gluster: &Gluster,
+                      locks: &Locks)
+                      -> ApiResult<Option<Lease>> {
+    let _lock = locks.lock(config, gluster, "leader", "reading the leader lease")?;
+    let now = Utc::now().timestamp();
+    Ok(read_lease(gluster)?.filter(|lease| lease.expires >= now))
+}
+
+// Remove the quotas and gid claims of deleted volumes, like `piragua
+// reconcile`
+fn reconcile(gluster: &Gluster, vol_name: &str) {
+    match quota::remove_stale(gluster, vol_name, false) {
+        Ok((removed, failed)) => {
+            for path in removed {
+                println!("Removed stale quota {}", path.display());
+            }
+            for path in failed {
+                println!("Removing stale quota {} failed", path.display());
+            }
+        }
+        Err(e) => println!("Removing stale quotas failed: {}", e),
+    }
+    match gids::release_orphans(gluster, false) {
+        Ok(released) => {
+            for (gid, _) in released {
+                println!("Released orphaned gid claim {}", gid);
+            }
+        }
+        Err(e) => println!("Releasing orphaned gids failed: {}", e),
+    }
+}
+
+// Run the leader's jobs, unless another instance leads.  Whether they ran
+fn run_jobs(leadership: &Leadership, jobs: impl FnOnce()) -> bool {
+    if !leadership.is_leader() {
+        return false;
+    }
+    jobs();
+    true
+}
+
+/// Heartbeat and run the leader's jobs in the background for as long as
+/// piragua runs
+pub fn spawn(leadership: Arc<Leadership>, live: Arc<LiveConfig>, gluster: Arc<Gluster>) {
+    // Separately so a slow clean up doesn't hold up heartbeats and lose the
+    // lease
+    let jobs_leadership = leadership.clone();
+    let jobs_live = live.clone();
+    let jobs_gluster = gluster.clone();
+    thread::spawn(move || loop {
+        let config = jobs_live.current();
+        if config.leader.jobs_interval == 0 {
+            // Check again in case a reload turns them on
+            thread::sleep(Duration::from_secs(config.leader.heartbeat));
+            continue;
+        }
+        thread::sleep(Duration::from_secs(config.leader.jobs_interval));
+        let vol_name = &config.gluster.volume;
+        if !run_jobs(&jobs_leadership, || reconcile(&jobs_gluster, vol_name)) {
+            println!("Not the leader, skipping the cluster wide clean up");
+        }
+    });
+    thread::spawn(move || {
+        // Only this thread takes the leader lock
+        let locks = Locks::new(&live.current().gluster.volume);
+        loop {
+            let config = live.current();
+            // Don't wait on the lock past the next heartbeat
+            let lock = LockConfig { timeout: config.leader.heartbeat, ..config.lock.clone() };
+            if let Err(e) = leadership.heartbeat(&config.leader, &lock, &gluster, &locks) {
+                println!("Leader heartbeat failed: {}", e);
+            }
+            thread::sleep(Duration::from_secs(config.leader.heartbeat));
+        }
+    });
+}
+
+#[test]
+fn test_can_take() {
+    let lease = |instance: &str, expires| Lease { instance: instance.into(),
+                                                  host: "gluster-1".into(),
+                                                  url: None,
+                                                  renewed: expires - 30,
+                                                  expires };
+    assert!(can_take(None, "a", 100));
+    assert!(can_take(Some(&lease("a", 130)), "a", 100));
+    assert!(!can_take(Some(&lease("b", 130)), "a", 100));
+    assert!(!can_take(Some(&lease("b", 100)), "a", 100));
+    assert!(can_take(Some(&lease("b", 99)), "a", 100));
+
+    let leadership = Leadership::new();
+    assert!(!leadership.is_leader());
+    let now = Utc::now().timestamp();
+    *leadership.lease.lock().unwrap() = Some(lease(leadership.instance(), now + 30));
+    assert!(leadership.is_leader());
+    assert!(leadership.require_leader("quota cleanup").is_ok());
+    // Our own lease ran out without being renewed
+    *leadership.lease.lock().unwrap() = Some(lease(leadership.instance(), now - 1));
+    assert!(!leadership.is_leader());
+    assert_eq!(leadership.leader(), None);
+}
+
+#[test]
+fn test_run_jobs() {
+    let leadership = Leadership::new();
+    let now = Utc::now().timestamp();
+    let lease = |instance: &str| Lease { instance: instance.into(),
+                                         host: "gluster-1".into(),
+                                         url: None,
+                                         renewed: now,
+                                         expires: now + 30 };
+    let mut ran = false;
+    // No leader yet
+    assert!(!run_jobs(&leadership, || ran = true));
+    // A follower
+    *leadership.lease.lock().unwrap() = Some(lease("another"));
+    assert!(!run_jobs(&leadership, || ran = true));
+    assert!(!ran);
+
+    let instance = leadership.instance().to_string();
+    *leadership.lease.lock().unwrap() = Some(lease(&instance));
+    assert!(run_jobs(&leadership, || ran = true));
+    assert!(ran);
+}
@@ -0,0 +1,260 @@
+//! Locks that stop piragua instances changing the same volume at once.
+//!
+//! Every instance shares the gluster volume, so the locks are kept on it: a
+//! POSIX write lock taken through gfapi on a file in .piragua/locks.  Volumes
+//! share 256 lock files by the first two characters of their id, so the
+//! files never have to be removed, which would race with instances locking
+//! them.  Gluster drops the locks of a client that goes away once the
+//! volume's network.ping-timeout passes, so a crashed instance can't keep a
+//! lock.  One that hangs on to a lock is found from the holder it wrote into
+//! the file, and once it has held it for lock.stale_after the lock is broken
+//! with `gluster volume clear-locks`.  POSIX locks don't keep out other
+//! threads of the same process so each instance also tracks the locks it
+//! holds itself.
+use std::{collections::HashSet,
+          path::{Path, PathBuf},
+          process::Command,
+          sync::{Condvar, Mutex},
+          thread,
+          time::{Duration, Instant}};
+
+use chrono::{TimeZone, Utc};
+use gfapi_sys::gluster::{Gluster, GlusterFile, PosixLockCmd};
+use libc::{flock, F_UNLCK, F_WRLCK, O_CREAT, O_RDONLY, O_RDWR, SEEK_SET, S_IRWXU};
+
+use crate::{config::LockConfig,
+            error::{parse_errno, ApiError, ApiResult},
+            topology::local_hostname,
+            volume::{VolumeId, STATE_DIR}};
+
+// How often to try a lock another instance holds
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+fn locks_dir() -> PathBuf { Path::new(STATE_DIR).join("locks") }
+
+/// Who holds a lock, written into its file
+#[derive(Debug, Deserialize, Serialize)]
+struct Holder {
+    host: String,
+    pid: u32,
+    operation: String,
+    /// Unix time it was taken
+    since: i64,
+}
+
+/// The locks of this instance
+pub struct Locks {
+    held: Mutex<HashSet<String>>,
+    released: Condvar,
+    host: String,
+    /// The gluster volume the lock files are on
+    volume: String,
+}
+
+/// A held lock, released when it's dropped
+pub struct LockGuard<'a> {
+    locks: &'a Locks,
+    name: String,
+    file: Option<GlusterFile>,
+}
+
+fn whole_file(l_type: i32) -> flock {
+    let mut lock: flock = unsafe { std::mem::zeroed() };
+    lock.l_type = l_type as i16;
+    lock.l_whence = SEEK_SET as i16;
+    lock
+}
+
+/// The lock file a volume uses
+pub fn lock_name(id: &VolumeId) -> String { id.as_str()[..2].to_string() }
+
+// Open a lock file, making the directories the first time
+fn open_lock_file(gluster: &Gluster, path: &Path) -> ApiResult<GlusterFile> {
+    match gluster.create(path, O_RDWR | O_CREAT, 0o644).map_err(ApiError::from) {
+        Err(ApiError::NotFound(_)) => {
+            for dir in &[Path::new(STATE_DIR).to_path_buf(), locks_dir()] {
+                match gluster.mkdir(dir, S_IRWXU).map_err(ApiError::from) {
+                    Ok(()) | Err(ApiError::Conflict(_)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(gluster.create(path, O_RDWR | O_CREAT, 0o644)?)
+        }
+        result => result,
+    }
+}
+
+// Who the file says holds the lock, if it can be read
+fn read_holder(gluster: &Gluster, path: &Path) -> Option<Holder> {
+    let file = gluster.open(path, O_RDONLY).ok()?;
+    let mut data = Vec::with_capacity(512);
+    file.read(&mut data, 512, 0).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+// Whether a holder has had the lock so long it must be stuck
+fn is_stale(config: &LockConfig, holder: &Holder, now: i64) -> bool {
+    now - holder.since > config.stale_after as i64
+}
+
+// Explain why a lock couldn't be taken in time
+fn busy_error(config: &LockConfig, holder: Option<Holder>, name: &str) -> ApiError {
+    let holder = match holder {
+        Some(holder) => holder,
+        None => return ApiError::Conflict(format!("Lock {} is held by another instance", name)),
+    };
+    let since = Utc.timestamp_opt(holder.since, 0)
+                   .single()
+                   .map(|t| t.to_rfc3339())
+                   .unwrap_or_else(|| "unknown".into());
+    let mut msg = format!("Lock {} is held by {} pid {} for {} since {}",
+                          name, holder.host, holder.pid, holder.operation, since);
+    if is_stale(config, &holder, Utc::now().timestamp()) {
+        msg.push_str(", which looks stuck and couldn't be broken.  Restarting that instance \
+                      releases it");
+        println!("Stale lock: {}", msg);
+    }
+    ApiError::Conflict(msg)
+}
+
+// Drop every POSIX lock on a lock file, whoever holds it.  Gluster only does
+// that itself for clients that disconnect, and a hung instance doesn't
+fn break_lock(volume: &str, path: &Path) -> ApiResult<()> {
+    let output = Command::new("gluster").args(&["volume", "clear-locks", volume])
+                                        .arg(Path::new("/").join(path))
+                                        .args(&["kind", "granted", "posix"])
+                                        .output();
+    let output =
+        output.map_err(|e| ApiError::BackendUnavailable(format!("Running gluster failed: {}", e)))?;
+    if !output.status.success() {
+        let msg = format!("Clearing the locks on {} failed: {}",
+                          path.display(),
+                          String::from_utf8_lossy(&output.stderr).trim());
+        return Err(ApiError::BackendUnavailable(msg));
+    }
+    Ok(())
+}
+
+impl Locks {
+    /// Locks kept on the gluster volume `volume`
+    pub fn new(volume: &str) -> Locks {
+        Locks { held: Mutex::new(HashSet::new()),
+                released: Condvar::new(),
+                host: local_hostname().unwrap_or_else(|| "unknown".into()),
+                volume: volume.into() }
+    }
+
+    /// Lock a volume for `operation`, ie "delete <id>", waiting up to
+    /// lock.timeout for the instance holding it
+    pub fn volume<'a>(&'a self,
+                      config: &LockConfig,
+                      gluster: &Gluster,
+                      id: &VolumeId,
+                      operation: &str)
+                      -> ApiResult<LockGuard<'a>> {
+        self.lock(config, gluster, &lock_name(id), operation)
+    }
+
+    /// Take the lock called `name`, for things that aren't a single volume
+    pub fn lock<'a>(&'a self,
+                    config: &LockConfig,
+                    gluster: &Gluster,
+                    name: &str,
+                    operation: &str)
+                    -> ApiResult<LockGuard<'a>> {
+        let deadline = Instant::now() + Duration::from_secs(config.timeout);
+        let path = locks_dir().join(name);
+
+        // Other threads first
+        let mut held = self.held.lock().unwrap();
+        while held.contains(name) {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(busy_error(config, read_holder(gluster, &path), name));
+            }
+            held = self.released.wait_timeout(held, deadline - now).unwrap().0;
+        }
+        held.insert(name.to_string());
+        drop(held);
+        // Given back by the guard if the file lock fails
+        let mut guard = LockGuard { locks: self, name: name.to_string(), file: None };
+
+        let file = open_lock_file(gluster, &path)?;
+        let mut checked_holder = false;
+        loop {
+            match file.posix_lock(PosixLockCmd::SetLock, &mut whole_file(F_WRLCK)) {
+                Ok(()) => break,
+                Err(e) => match parse_errno(&e.to_string()) {
+                    // Only the first time it's busy, a broken lock goes to
+                    // whoever asks for it next
+                    Some(libc::EAGAIN) | Some(libc::EACCES) if !checked_holder => {
+                        checked_holder = true;
+                        let holder = match read_holder(gluster, &path) {
+                            Some(holder) => holder,
+                            None => continue,
+                        };
+                        if !is_stale(config, &holder, Utc::now().timestamp()) {
+                            continue;
+                        }
+                        println!("Breaking lock {} held by {} pid {} for {} since {}",
+                                 name, holder.host, holder.pid, holder.operation, holder.since);
+                        if let Err(e) = break_lock(&self.volume, &path) {
+                            println!("{}", e);
+                        }
+                    }
+                    Some(libc::EAGAIN) | Some(libc::EACCES) if Instant::now() < deadline => {
+                        thread::sleep(RETRY_INTERVAL)
+                    }
+                    Some(libc::EAGAIN) | Some(libc::EACCES) => {
+                        return Err(busy_error(config, read_holder(gluster, &path), name));
+                    }
+                    _ => return Err(e.into()),
+                },
+            }
+        }
+        let holder = Holder { host: self.host.clone(),
+                              pid: std::process::id(),
+                              operation: operation.into(),
+                              since: Utc::now().timestamp() };
+        gluster.truncate(&path, 0)?;
+        file.write(&serde_json::to_vec(&holder)?, 0)?;
+        guard.file = Some(file);
+        Ok(guard)
+    }
+}
+
+impl<'a> Drop for LockGuard<'a> {
+    fn drop(&mut self) {
+        // Closing the file would drop the lock too, this is just sooner
+        if let Some(file) = self.file.take() {
+            if let Err(e) = file.posix_lock(PosixLockCmd::SetLock, &mut whole_file(F_UNLCK)) {
+                println!("Unlocking {} failed, closing it instead: {}", self.name, e);
+            }
+        }
+        self.locks.held.lock().unwrap().remove(&self.name);
+        self.locks.released.notify_all();
+    }
+}
+
+#[test]
+fn test_lock() {
+    let id: VolumeId = "3fa85f64-5717-4562-b3fc-2c963f66afa6".parse().unwrap();
+    assert_eq!(locks_dir().join(lock_name(&id)), PathBuf::from(".piragua/locks/3f"));
+
+    let config = LockConfig::default();
+    let holder = |age| {
+        Some(Holder { host: "gluster-1".into(),
+                      pid: 42,
+                      operation: "expand".into(),
+                      since: Utc::now().timestamp() - age })
+    };
+    let msg = busy_error(&config, holder(5), "3f").to_string();
+    assert!(msg.contains("gluster-1 pid 42 for expand") && !msg.contains("stuck"));
+    assert!(busy_error(&config, holder(config.stale_after as i64 + 5), "3f").to_string()
+                                                                            .contains("stuck"));
+    assert_eq!(busy_error(&config, None, "3f").code(), "conflict");
+    // Garbage in the lock file
+    let mut bad = holder(0).unwrap();
+    bad.since = i64::max_value();
+    assert!(busy_error(&config, Some(bad), "3f").to_string().contains("since unknown"));
+}
@@ -8,28 +8,50 @@ extern crate rocket;
 extern crate serde_derive;
 use serde_json;
 
+mod acl;
+mod auth;
+mod cli;
+mod config;
+mod error;
+mod gids;
+mod glusterd;
+mod leader;
+mod lock;
+mod ownership;
+mod quota;
+mod selinux;
+mod tags;
+mod token;
+mod topology;
+mod volume;
+
 use std::{collections::HashMap,
-          env,
-          fs::{self, File},
-          io::{BufRead, BufReader, Cursor, Error, ErrorKind, Result as IOResult},
-          net::IpAddr,
+          io::{Cursor, Result as IOResult},
           path::{Path, PathBuf},
           str::FromStr,
-          sync::Mutex};
+          sync::{Arc, Mutex},
+          thread};
 
-use base64::decode as base64_decode;
-use clap::{App, Arg};
+use auth::{Admin, AuthError, Jwt};
+use clap::{App, Arg, SubCommand};
+use config::{ConfigSource, LiveConfig, DEFAULT_CONFIG_PATH, STARTUP_ONLY};
+use error::{ApiError, ApiResult};
 use gfapi_sys::gluster::*;
-use gluster::{get_local_ip, peer::peer_list, volume::volume_add_quota};
+use gluster::peer::{peer_list, Peer};
+use glusterd::{get_local_uuid, get_peer_uuids};
 use itertools::Itertools;
-use jsonwebtoken::{decode, Algorithm, Validation};
-use libc::{DT_DIR, S_IRGRP, S_IRUSR, S_IRWXU, S_IWGRP, S_IXGRP, S_IXUSR};
-use rocket::{http::{hyper::header::Location, ContentType, Status},
-             request::{self, FromRequest},
+use leader::{Leadership, Lease};
+use libc::{DT_DIR, S_IRWXU};
+use lock::Locks;
+use ownership::{Gid, Ownership, OwnershipRequest};
+use rocket::{config::{Config as RocketConfig, Environment, LoggingLevel},
+             http::{hyper::header::Location, uri::Origin, ContentType, Status},
+             request::LenientForm,
              response::status::Created,
-             Outcome, Request, Response, State};
+             Request, Response, State};
 use rocket_contrib::json::Json;
 use uuid::Uuid;
+use volume::{volume_ids, VolumeFilter, VolumeId, VolumeName, VolumeQuery};
 
 #[derive(Debug, Serialize)]
 struct GlusterClusters {
@@ -48,6 +70,14 @@ struct VolumeInfo {
     snapshot: Snapshot,
     mount: Mount,
     bricks: Vec<Brick>,
+    tags: tags::Tags,
+    acl: acl::Acl,
+    uid: u32,
+    gid: u32,
+    /// Octal permissions of the volume directory, ie "2770"
+    mode: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    selinux_label: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -56,6 +86,12 @@ struct ExpandVolumeRequest {
     expand_size: u64,
 }
 
+#[derive(Debug, Deserialize)]
+struct ResizeVolumeRequest {
+    /// New size in GB
+    size: u64,
+}
+
 #[derive(Debug, Deserialize)]
 struct CreateVolumeRequest {
     /// Size in GB
@@ -66,7 +102,19 @@ struct CreateVolumeRequest {
     name: String,
     durability: Option<Durability>,
     gid: Option<u64>,
+    /// Owner of the volume directory
+    uid: Option<u32>,
+    /// Octal permissions, ie "2770"
+    mode: Option<String>,
+    setgid: Option<bool>,
     snapshot: Snapshot,
+    #[serde(default)]
+    tags: tags::Tags,
+    /// Extra users and groups to give access to the volume directory
+    #[serde(default)]
+    acl: acl::Acl,
+    /// MCS categories added to the configured SELinux label, ie "c1,c5"
+    selinux_categories: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -127,9 +175,19 @@ struct Brick {
     device: String,
 }
 
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum VolumeEntry {
+    Id(String),
+    Details(VolumeInfo),
+}
+
 #[derive(Debug, Serialize)]
 struct VolumeList {
-    volumes: Vec<String>,
+    volumes: Vec<VolumeEntry>,
+    /// Where the next page starts when limit cut the list short
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_offset: Option<usize>,
 }
 
 #[derive(Debug, Serialize)]
@@ -162,6 +220,7 @@ struct DeviceInfo {
     name: PathBuf, //": "/dev/sdh",
     storage: Storage,
     id: String,
+    state: String,
     bricks: Vec<Brick>,
 }
 
@@ -174,7 +233,7 @@ struct Storage {
 
 #[derive(Debug, Serialize)]
 struct NodeInfoResponse {
-    zone: u8,
+    zone: u32,
     hostnames: ManagedHosts,
     cluster: String,
     id: Uuid,
@@ -182,95 +241,48 @@ struct NodeInfoResponse {
     devices: Vec<DeviceInfo>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    iss: String,
-    iat: u64,
-    exp: u64,
-    qsh: String,
-}
-
 #[derive(Debug, Serialize)]
 struct Version {
     version: String,
 }
 
-// Json Web Token
-struct Jwt(Claims);
-
-impl<'a, 'r> FromRequest<'a, 'r> for Jwt {
-    type Error = String;
-
-    fn from_request(request: &'a Request<'r>) -> request::Outcome<Jwt, Self::Error> {
-        let secret = match env::var("JWT_SECRET") {
-            Ok(s) => s,
-            Err(e) => {
-                return Outcome::Failure((Status::PreconditionFailed, e.to_string()));
-            }
-        };
-        let secret_decoded = match base64_decode(&secret) {
-            Ok(s) => s,
-            Err(e) => {
-                println!("Secret decoding failed: {:?}", e);
-                return Outcome::Failure((Status::PreconditionFailed, e.to_string()));
-            }
-        };
-        let token_header = request.headers().get_one("Authorization");
-        match token_header {
-            Some(auth_token) => {
-                // Set the default params for validation
-                let mut validate = Validation::default();
-                validate.algorithms = vec![Algorithm::HS256]; // set our Algorithm
-                validate.leeway = 1000 * 60; // Add 1 minute of leeway for clock skew
-                validate.validate_nbf = false;
-
-                let auth_parts: Vec<&str> = auth_token.split_whitespace().collect();
-                let token_data = match decode::<Claims>(auth_parts[1], &secret_decoded, &validate) {
-                    Ok(data) => data,
-                    Err(e) => {
-                        println!("jwt decode failed: {:?}", e);
-                        return Outcome::Failure((Status::BadRequest, e.to_string()));
-                    }
-                };
-                Outcome::Success(Jwt(token_data.claims))
-            }
-            None => Outcome::Failure((Status::BadRequest, "JWT token missing from request".into())),
-        }
-    }
-}
-
 #[post("/clusters", format = "application/json")]
-fn create_cluster(_web_token: Jwt) -> Created<Json<GlusterClusters>> {
-    let clusters =
-        GlusterClusters { id: "cluster-test".to_string(), nodes: vec![], volumes: vec![] };
-
-    Created("".to_string(), Some(Json(clusters)))
+fn create_cluster(_web_token: Jwt,
+                  live: State<'_, Arc<LiveConfig>>,
+                  vol_name: State<'_, String>)
+                  -> ApiResult<Created<Json<GlusterClusters>>> {
+    // There's only ever the one cluster so hand that back
+    let config = live.current();
+    let cluster_id = topology::cluster_id(&config.gluster, &vol_name)?;
+    let clusters = GlusterClusters { id: cluster_id.clone(), nodes: vec![], volumes: vec![] };
+
+    Ok(Created(format!("/clusters/{}", cluster_id), Some(Json(clusters))))
 }
 
 #[get("/clusters/<cluster_id>")]
 fn get_cluster_info(_web_token: Jwt,
                     cluster_id: String,
-                    state: State<'_, Gluster>)
-                    -> Result<Json<GlusterClusters>, String> {
+                    live: State<'_, Arc<LiveConfig>>,
+                    vol_name: State<'_, String>,
+                    state: State<'_, Arc<Gluster>>)
+                    -> ApiResult<Json<GlusterClusters>> {
+    let config = live.current();
     let mut vol_list: Vec<String> = vec![];
 
+    if cluster_id != topology::cluster_id(&config.gluster, &vol_name)? {
+        return Err(ApiError::NotFound(format!("Unable to find cluster {}", cluster_id)));
+    }
+
     // Get all the peers in the cluster
-    let local_uuid = get_local_uuid().map_err(|e| e.to_string())?;
-    let mut peer_uuids = get_peer_uuids().map_err(|e| e.to_string())?;
+    let local_uuid = get_local_uuid(&config.gluster.state_dir)?;
+    let mut peer_uuids = get_peer_uuids(&config.gluster.state_dir)?;
     if let Some(local) = local_uuid {
         peer_uuids.push(local);
     }
 
-    //List all the top level directories and return them as volumes
-    let d = state.opendir(&Path::new("/")).map_err(|e| e.to_string())?;
-    for dir_entry in d {
-        let dir_entry = dir_entry.map_err(|e| e.to_string())?;
-        let dir_name = format!("{}", dir_entry.path.display());
-        // Skip the parent and current dir entries
-        if dir_name == ".." || dir_name == "." {
-            continue;
-        }
-        vol_list.push(dir_name);
+    //List all the top level volume directories
+    for id in volume_ids(&state)? {
+        vol_list.push(id.to_string());
     }
 
     let clusters = GlusterClusters { id: cluster_id,
@@ -284,11 +296,16 @@ fn get_cluster_info(_web_token: Jwt,
 }
 
 #[get("/clusters")]
-fn list_clusters(_web_token: Jwt, state: State<'_, String>) -> Json<ClusterList> {
+fn list_clusters(_web_token: Jwt,
+                 live: State<'_, Arc<LiveConfig>>,
+                 vol_name: State<'_, String>)
+                 -> ApiResult<Json<ClusterList>> {
     // Only return the single volume as a cluster
-    let clusters = ClusterList { clusters: vec![state.inner().clone()] };
-    println!("list clusters: {}", serde_json::to_string(&clusters).unwrap());
-    Json(clusters)
+    let config = live.current();
+    let clusters =
+        ClusterList { clusters: vec![topology::cluster_id(&config.gluster, &vol_name)?] };
+    println!("list clusters: {}", serde_json::to_string(&clusters)?);
+    Ok(Json(clusters))
 }
 
 #[delete("/clusters/<_id>")]
@@ -297,51 +314,41 @@ fn delete_cluster(_web_token: Jwt, _id: String) {
 }
 
 #[get("/nodes/<id>")]
-fn get_node_info(_web_token: Jwt, id: String) -> Result<Json<NodeInfoResponse>, String> {
+fn get_node_info(_web_token: Jwt,
+                 id: String,
+                 live: State<'_, Arc<LiveConfig>>,
+                 vol_name: State<'_, String>)
+                 -> ApiResult<Json<NodeInfoResponse>> {
+    let config = live.current();
     // heketi thinks this is a mgmt node
     // get info on 192.168.1.2
-    let node_uuid = Uuid::from_str(&id).map_err(|e| e.to_string())?;
-    let local_uuid = get_local_uuid().map_err(|e| e.to_string())?;
-    let host_ip: IpAddr;
-
-    match local_uuid {
-        Some(local) => {
-            //is this my local uuid?
-            if local == node_uuid {
-                host_ip = get_local_ip().map_err(|e| e.to_string())?;
-            } else {
-                // It's not so lets see if it's one of my peers
-                host_ip = match get_peer_info(&node_uuid).map_err(|e| e.to_string())? {
-                    Some(ip) => ip,
-                    None => {
-                        //It's not my local or a peer.  I don't know what this is
-                        println!("get_node_info discovery failed for: {}", id);
-                        return Err(format!("Unable to find info for {}", id));
-                    }
-                };
-            }
+    let node_uuid =
+        Uuid::from_str(&id).map_err(|e| ApiError::InvalidInput(format!("{}: {}", id, e)))?;
+    if get_local_uuid(&config.gluster.state_dir)?.is_none() {
+        // I can't find my local uuid so fail. Is gluster not running?
+        return Err(ApiError::BackendUnavailable("Unable to find local gluster uuid".into()));
+    }
+    let peers = peers_or_empty();
+    let nodes = topology::get_nodes(&config.gluster.state_dir,
+                                    &vol_name,
+                                    &topology::cluster_id(&config.gluster, &vol_name)?,
+                                    &config.nodes,
+                                    &peers)?;
+    match nodes.into_iter().find(|n| n.id == node_uuid) {
+        Some(resp) => {
+            println!("node info response: {}", serde_json::to_string(&resp)?);
+            Ok(Json(resp))
         }
         None => {
-            // I can't find my local uuid so fail. Is gluster not running?
-            return Err("Unable to find local gluster uuid".to_string());
+            //It's not my local or a peer.  I don't know what this is
+            println!("get_node_info discovery failed for: {}", id);
+            Err(ApiError::NotFound(format!("Unable to find info for {}", id)))
         }
     }
-
-    let resp = NodeInfoResponse { zone: 1,
-                                  id: node_uuid,
-                                  cluster: "cluster-test".into(),
-                                  hostnames: ManagedHosts { // Everyone manages themselves
-                                                            manage: vec![host_ip.to_string()],
-                                                            storage:
-                                                                vec![host_ip.to_string()] },
-                                  devices: vec![],
-                                  state: "online".into() };
-    println!("node info response: {}", serde_json::to_string(&resp).map_err(|e| e.to_string())?);
-    Ok(Json(resp))
 }
 
 #[delete("/nodes/<_id>")]
-fn delete_node<'a>(_web_token: Jwt, _id: String) -> Result<Response<'a>, String> {
+fn delete_node<'a>(_web_token: Jwt, _id: String) -> ApiResult<Response<'a>> {
     //NOPE you're not allowed
     let mut response = Response::new();
     response.set_status(Status::new(204, "Volume created"));
@@ -349,7 +356,7 @@ fn delete_node<'a>(_web_token: Jwt, _id: String) -> Result<Response<'a>, String>
 }
 
 #[post("/nodes", format = "application/json", data = "<_input>")]
-fn add_node<'a>(_web_token: Jwt, _input: Json<AddNodeRequest>) -> Result<Response<'a>, String> {
+fn add_node<'a>(_web_token: Jwt, _input: Json<AddNodeRequest>) -> ApiResult<Response<'a>> {
     //NOPE you're not allowed
     let mut response = Response::new();
     response.set_status(Status::new(204, "Node created"));
@@ -357,7 +364,7 @@ fn add_node<'a>(_web_token: Jwt, _input: Json<AddNodeRequest>) -> Result<Respons
 }
 
 #[post("/devices", format = "application/json", data = "<_input>")]
-fn add_device<'a>(_web_token: Jwt, _input: Json<AddDeviceRequest>) -> Result<Response<'a>, String> {
+fn add_device<'a>(_web_token: Jwt, _input: Json<AddDeviceRequest>) -> ApiResult<Response<'a>> {
     //NOPE you're not allowed
     let mut response = Response::new();
     response.set_status(Status::new(204, "Device created"));
@@ -365,75 +372,56 @@ fn add_device<'a>(_web_token: Jwt, _input: Json<AddDeviceRequest>) -> Result<Res
 }
 
 #[delete("/devices/<_id>")]
-fn delete_device<'a>(_web_token: Jwt, _id: String) -> Result<Response<'a>, String> {
+fn delete_device<'a>(_web_token: Jwt, _id: String) -> ApiResult<Response<'a>> {
     //NOPE you're not allowed
     let mut response = Response::new();
     response.set_status(Status::new(204, "Device deleted"));
     Ok(response)
 }
 
-#[get("/devices/<_device_id>")]
-fn get_device_info(_web_token: Jwt, _device_id: String) -> Json<DeviceInfo> {
-    let device_info = DeviceInfo { name: PathBuf::from("/dev/sda"), //": "/dev/sdh",
-                                   storage: Storage { total: 0, free: 0, used: 0 },
-                                   id: "".into(),
-                                   bricks: vec![] };
-    Json(device_info)
+#[get("/devices/<device_id>")]
+fn get_device_info(_web_token: Jwt,
+                   device_id: String,
+                   live: State<'_, Arc<LiveConfig>>,
+                   vol_name: State<'_, String>)
+                   -> ApiResult<Json<DeviceInfo>> {
+    let config = live.current();
+    let peers = peers_or_empty();
+    let device_info = topology::get_device(&config.gluster.state_dir,
+                                           &vol_name,
+                                           &topology::cluster_id(&config.gluster, &vol_name)?,
+                                           &config.nodes,
+                                           &peers,
+                                           &device_id)?;
+    Ok(Json(device_info))
+}
+
+#[get("/topology")]
+fn get_topology(_web_token: Jwt,
+                live: State<'_, Arc<LiveConfig>>,
+                vol_name: State<'_, String>,
+                state: State<'_, Arc<Gluster>>)
+                -> ApiResult<Json<topology::TopologyInfo>> {
+    let config = live.current();
+    let peers = peers_or_empty();
+    let topology = topology::get_topology(&config.gluster.state_dir,
+                                          &state,
+                                          &vol_name,
+                                          &topology::cluster_id(&config.gluster, &vol_name)?,
+                                          &config.nodes,
+                                          &peers)?;
+    Ok(Json(topology))
 }
 
 #[post("/volumes", format = "application/json", data = "<input>")]
-fn create_volume<'a>(_web_token: Jwt,
+fn create_volume<'a>(web_token: Jwt,
                      input: Json<CreateVolumeRequest>,
-                     state: State<'_, Gluster>,
+                     live: State<'_, Arc<LiveConfig>>,
+                     state: State<'_, Arc<Gluster>>,
                      vol_name: State<'_, String>)
-                     -> Result<Response<'a>, String> {
+                     -> ApiResult<Response<'a>> {
     println!("volume request: {:#?}", input);
-
-    let id = Uuid::new_v4().to_hyphenated().to_string();
-    let name = if input.name == "" {
-        format!("vol_{}", id)
-    } else {
-        if input.name.chars().any(invalid_chars) {
-            println!("Invalid characters detected in name");
-            return Err("Only numbers, letters, '-' or '_' are allowed in the volume name".into());
-        }
-        input.name.clone()
-    };
-
-    let top_dir = Path::new(&id);
-    let sub_dir = PathBuf::from(format!("{}/{}", id, name));
-
-    // Create the mount point on the cluster
-    if !state.exists(&top_dir).map_err(|e| e.to_string())? {
-        // Make the top level dir
-        state.mkdir(&top_dir, S_IRWXU).map_err(|e| e.to_string())?;
-        // Make the subdir
-        state.mkdir(&sub_dir, S_IRWXU).map_err(|e| e.to_string())?;
-    }
-
-    // Change the group id on it to match the requested one
-    // root and the requesting user can read the directory
-    // If gid is None we don't do anything.
-    if let Some(gid) = input.gid {
-        state.chown(&top_dir, 0, gid as u32).map_err(|e| e.to_string())?;
-        state.chown(&sub_dir, 0, gid as u32).map_err(|e| e.to_string())?;
-    }
-
-    // root can read/execute and requesting user can read/write/execute
-    state.chmod(&top_dir, S_IRUSR | S_IXUSR | S_IRGRP | S_IWGRP | S_IXGRP)
-         .map_err(|e| e.to_string())?;
-    state.chmod(&sub_dir, S_IRUSR | S_IXUSR | S_IRGRP | S_IWGRP | S_IXGRP)
-         .map_err(|e| e.to_string())?;
-
-    let quota_path = PathBuf::from(format!("/{}", id));
-    println!("Adding {}GB sized quota to: {}", input.size, quota_path.display());
-    // Convert input.size to bytes
-    match volume_add_quota(&vol_name, &quota_path, input.size * 1024 * 1024 * 1024) {
-        Ok(_) => {}
-        Err(e) => {
-            println!("volume_add_quota_failed: {}", e.to_string());
-        }
-    }
+    let (id, name) = create(&live.current(), &state, &vol_name, &web_token.claims.iss, &input)?;
 
     let mut response = Response::new();
     response.set_header(Location(format!("/volumes/{volume}/{id}/{name}",
@@ -445,72 +433,125 @@ fn create_volume<'a>(_web_token: Jwt,
     Ok(response)
 }
 
-// List the peer uuids but not the local one.  Use get_local_uuid for that
-fn get_peer_uuids() -> IOResult<Vec<Uuid>> {
-    let mut uuids: Vec<Uuid> = Vec::new();
-    for entry in fs::read_dir(Path::new("/var/lib/glusterd/peers"))? {
-        let entry = entry?;
-        let u = Uuid::from_str(&entry.file_name().to_string_lossy()).map_err(|e| {
-                                                                        Error::new(ErrorKind::Other,
-                                                                                   e.to_string())
-                                                                    })?;
-        uuids.push(u);
-    }
-    Ok(uuids)
-}
-
-// Get the local uuid for this glusterd
-fn get_local_uuid() -> IOResult<Option<Uuid>> {
-    let f = File::open("/var/lib/glusterd/glusterd.info")?;
-    let f = BufReader::new(f);
-    for line in f.lines() {
-        let l = line?;
-        if l.starts_with("UUID") {
-            let l = l.replace("UUID=", "");
-            let guid = Uuid::from_str(&l).map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
-            return Ok(Some(guid));
+// Make a volume for a client whose tokens are issued by `class`
+fn create(config: &config::Config,
+          state: &Gluster,
+          vol_name: &str,
+          class: &str,
+          input: &CreateVolumeRequest)
+          -> ApiResult<(VolumeId, VolumeName)> {
+    // Size is in GB.  Fall back to the configured default if none was asked for
+    let size = if input.size == 0 { config.quota.default_size } else { input.size };
+    quota::check_max_size(&config.quota, size)?;
+
+    tags::validate(&input.tags)?;
+    acl::validate(&input.acl)?;
+    let request = OwnershipRequest { uid: input.uid,
+                                     gid: input.gid,
+                                     mode: input.mode.clone(),
+                                     setgid: input.setgid };
+    let policy = ownership::resolve(&config.volumes, Some(class), &request)?;
+    let label = selinux::resolve(&config.volumes,
+                                 Some(class),
+                                 input.selinux_categories.as_ref().map(|c| c.as_str()))?;
+    let id = VolumeId::new_v4();
+    let name = if input.name == "" {
+        VolumeName::from_str(&format!("vol_{}", id))?
+    } else {
+        VolumeName::from_str(&input.name)?
+    };
+    // Nothing else knows the new id, so there's no volume lock to take.  The
+    // gid is claimed before the volume's directory exists, which
+    // gids::release_orphans allows for
+
+    let gid = match policy.gid {
+        Gid::None => 0,
+        Gid::Fixed(gid) => {
+            gids::reserve(state, gid, &id)?;
+            gid
         }
+        Gid::Allocate { min, max } => gids::allocate(state, min, max, &id)?,
+    };
+    let ownership = Ownership { uid: policy.uid, gid, mode: policy.mode };
+    if let Err(e) = make_volume_dirs(state,
+                                     &id,
+                                     &name,
+                                     &ownership,
+                                     &input.acl,
+                                     &input.tags,
+                                     label.as_ref().map(|l| l.as_str()))
+    {
+        // Don't leave behind a directory or hold on to a gid for a volume
+        // the client is told never was.  The id is new so the directory is
+        // only ever ours
+        if let Err(remove_err) = state.remove_dir_all(Path::new(id.as_str())) {
+            println!("Removing {} failed: {}", id, remove_err);
+        }
+        if let Err(release_err) = gids::release_gid(state, gid, &id) {
+            println!("Releasing gid {} failed: {}", gid, release_err);
+        }
+        return Err(e);
+    }
+    // Listing falls back to the top dir's mtime without it
+    if let Err(e) = volume::set_created(state, &id, &chrono::Utc::now()) {
+        println!("Recording when {} was created failed: {}", id, e);
     }
-    Ok(None)
-}
 
-// Get the gluster peer ip address
-fn get_peer_info(uuid: &Uuid) -> IOResult<Option<IpAddr>> {
-    let f = File::open(format!("/var/lib/glusterd/peers/{}", uuid.to_hyphenated()))?;
-    let f = BufReader::new(f);
-    for line in f.lines() {
-        let l = line?;
-        if l.starts_with("hostname") {
-            let l = l.replace("hostname1=", "");
-            let ip_addr = IpAddr::from_str(&l).map_err(|e| Error::new(ErrorKind::Other, e))?;
-            return Ok(Some(ip_addr));
-        }
+    println!("Adding {}GB sized quota to: /{}", size, id);
+    // Convert size to bytes
+    if let Err(e) = quota::set_limit(&config.quota, state, vol_name, &id, size * quota::GB) {
+        println!("Setting the quota on {} failed: {}", id, e);
     }
-    Ok(None)
+    Ok((id, name))
 }
 
-fn get_gluster_vol(vol_id: &str) -> IOResult<HashMap<String, String>> {
-    let vol_file = File::open(format!("/var/lib/glusterd/vols/{}/info", vol_id))?;
-    let mut vol_data = HashMap::new();
-    let f = BufReader::new(vol_file);
-    for line in f.lines() {
-        let l = line?;
-        let parts: Vec<&str> = l.split('=').collect();
-        if parts.len() != 2 {
-            // Skip broken data
-            continue;
-        }
-        vol_data.insert(parts[0].to_string(), parts[1].to_string());
+// Create the mount point on the cluster
+fn make_volume_dirs(state: &Gluster,
+                    id: &VolumeId,
+                    name: &VolumeName,
+                    ownership: &Ownership,
+                    acl: &acl::Acl,
+                    tags: &tags::Tags,
+                    label: Option<&str>)
+                    -> ApiResult<()> {
+    let top_dir = Path::new(id.as_str());
+    let sub_dir = PathBuf::from(format!("{}/{}", id, name));
+
+    if !state.exists(&top_dir)? {
+        // Make the top level dir
+        state.mkdir(&top_dir, S_IRWXU)?;
+        // Make the subdir
+        state.mkdir(&sub_dir, S_IRWXU)?;
     }
-    Ok(vol_data)
+
+    // By default root can read/execute and the requesting group can
+    // read/write/execute
+    println!("Setting {} to {}:{} {:o}", id, ownership.uid, ownership.gid, ownership.mode);
+    ownership::apply(state, &top_dir, ownership)?;
+    ownership::apply(state, &sub_dir, ownership)?;
+    // After chmod, which would change the ACL's mask
+    if !acl.is_empty() {
+        println!("Setting ACL on {} to {:?}", sub_dir.display(), acl);
+        acl::apply_traverse(state, &top_dir, acl, ownership.mode)?;
+        acl::apply(state, &sub_dir, acl, ownership.mode)?;
+    }
+    if let Some(label) = label {
+        println!("Labelling {} {}", id, label);
+        selinux::apply(state, &top_dir, label)?;
+        selinux::apply(state, &sub_dir, label)?;
+    }
+    if !tags.is_empty() {
+        tags::write(state, id, tags)?;
+    }
+    Ok(())
 }
 
-fn get_subdir_name(p: &Path, g: &Gluster) -> Result<Option<String>, String> {
+fn get_subdir_name(p: &Path, g: &Gluster) -> ApiResult<Option<String>> {
     let this = Path::new(".");
     let parent = Path::new("..");
-    let d = g.opendir(p).map_err(|e| e.to_string())?;
+    let d = g.opendir(p)?;
     for dir_entry in d {
-        let dir_entry = dir_entry.map_err(|e| e.to_string())?;
+        let dir_entry = dir_entry?;
         if dir_entry.path == this || dir_entry.path == parent {
             continue;
         }
@@ -521,92 +562,41 @@ fn get_subdir_name(p: &Path, g: &Gluster) -> Result<Option<String>, String> {
     Ok(None)
 }
 
-#[test]
-fn test_valid_chars() {
-    let vol_name = "it_works-0";
-    assert_eq!(vol_name.chars().any(|c| invalid_chars(c)), false);
-
-    let vol_name = "it_fails!&";
-    assert_eq!(vol_name.chars().any(|c| invalid_chars(c)), true);
-}
-
-// Returns true if this char is invalid
-fn invalid_chars(c: char) -> bool { !(c.is_alphabetic() || c.is_numeric() || c == '-' || c == '_') }
-
 #[get("/volumes/<id>")]
 fn get_volume_info_by_id<'a>(_web_token: Jwt,
-                             id: String,
+                             id: Result<VolumeId, ApiError>,
+                             live: State<'_, Arc<LiveConfig>>,
                              vol_name: State<'_, String>,
-                             state: State<'_, Gluster>)
-                             -> Result<Response<'a>, String> {
-    let vol_exists = state.exists(&Path::new(&id)).map_err(|e| e.to_string())?;
+                             state: State<'_, Arc<Gluster>>)
+                             -> ApiResult<Response<'a>> {
+    let id = id?;
+    let vol_exists = state.exists(&Path::new(id.as_str()))?;
 
     if !vol_exists {
         println!("volume {} doesn't exist.  Returning NoContent", id);
         let response = Response::build().status(Status::NoContent).finalize();
         return Ok(response);
     }
-    let peers = peer_list().map_err(|e| e.to_string())?;
-    let backup_servers: Vec<String> = peers.iter().map(|ref p| p.hostname.clone()).collect();
-    let name = get_subdir_name(&Path::new(&id), &state)?;
-
-    let mut mount_options: HashMap<String, String> = HashMap::new();
-    mount_options.insert("backup-volfile-servers".into(),
-                         backup_servers.iter().join(",").to_string());
-    let quota_path = PathBuf::from(format!("/{}", id));
-    let quota_size: u64 = match state.statvfs(&quota_path) {
-        Ok(stat) => {
-            //This is in bytes.  We need to convert to GB
-            (stat.f_frsize * stat.f_blocks) / 1024 / 1024 / 1024
-        }
-        Err(e) => {
-            println!("statvfs error for {}: {:?}", *vol_name, e);
-            0
-        }
-    };
-
-    let response_data =
-        VolumeInfo { name: format!("{volume}/{id}/{name}",
-                                   volume = *vol_name,
-                                   id = id,
-                                   name = name.clone().unwrap_or_else(|| "".into())),
-                     id: id.clone(),
-                     cluster: "cluster-test".into(),
-                     size: quota_size,
-                     durability: Durability { mount_type: Some(VolumeType::Replicate),
-                                              replicate:
-                                                  Some(ReplicaDurability { replica: Some(3) }) },
-                     snapshot: Snapshot { enable: Some(true), factor: Some(1.20) },
-                     mount: Mount { glusterfs: GlusterFsMount { hosts: backup_servers,
-                                                                device: format!(
-                    "{server}:/{volume}/{id}/{name}",
-                    server = peers[0].hostname,
-                    volume = *vol_name,
-                    id = id,
-                    name = name.unwrap_or_else(|| "".into())
-                ),
-                                                                options: mount_options } },
-                     bricks: vec![] };
-    println!("VolumeInfo: {}", serde_json::to_string(&response_data).map_err(|e| e.to_string())?);
-    let response = Response::build()
-        .header(ContentType::JSON)
-        .raw_header("X-Pending", "false")
-        .sized_body(Cursor::new(
-            serde_json::to_string(&response_data).map_err(|e| e.to_string())?,
-        )).finalize();
-    println!("response: {:#?}", response);
-    Ok(response)
+    let peers = peer_list()?;
+    let name = get_subdir_name(&Path::new(id.as_str()), &state)?.unwrap_or_else(|| "".into());
+    let cluster_id = topology::cluster_id(&live.current().gluster, &vol_name)?;
+    let response_data = volume_info(&state, &vol_name, &cluster_id, &id, &name, &peers)?;
+    volume_info_response(&response_data)
 }
 
 #[get("/volumes/<_volume>/<id>/<name>")]
 fn get_volume_info<'a>(_web_token: Jwt,
-                       _volume: String,
-                       id: String,
-                       name: String,
+                       _volume: Result<VolumeName, ApiError>,
+                       id: Result<VolumeId, ApiError>,
+                       name: Result<VolumeName, ApiError>,
+                       live: State<'_, Arc<LiveConfig>>,
                        vol_name: State<'_, String>,
-                       state: State<'_, Gluster>)
-                       -> Result<Response<'a>, String> {
-    let vol_exists = state.exists(&Path::new(&id)).map_err(|e| e.to_string())?;
+                       state: State<'_, Arc<Gluster>>)
+                       -> ApiResult<Response<'a>> {
+    _volume?;
+    let id = id?;
+    let name = name?;
+    let vol_exists = state.exists(&Path::new(id.as_str()))?;
 
     if !vol_exists {
         //Unable to find volume, returning NoContent
@@ -614,107 +604,288 @@ fn get_volume_info<'a>(_web_token: Jwt,
         let response = Response::build().status(Status::NoContent).finalize();
         return Ok(response);
     }
-    let vol_info = get_gluster_vol(&vol_name).map_err(|e| e.to_string())?;
-    let peers = peer_list().map_err(|e| e.to_string())?;
-
-    for item in &vol_info {
-        if item.0.starts_with("brick") {
-            /*
-            brick_info.push(Brick {
-                id: "".into(),
-                //TODO: what is this supposed to be?
-                path: brick.path, //"/gluster/brick_aaaaaad2e40df882180479024ac4c24c8/brick",
-                size: 10,
-                //TODO: what do I return here for node and device?
-                node: "".into(),
-                device: "".into(),
-            });
-            */
-        }
-    }
-    let backup_servers: Vec<String> = peers.iter().map(|ref p| p.hostname.clone()).collect();
+    let config = live.current();
+    let peers = peer_list()?;
+    let cluster_id = topology::cluster_id(&config.gluster, &vol_name)?;
+    let response_data = volume_info(&state, &vol_name, &cluster_id, &id, name.as_str(), &peers)?;
+    volume_info_response(&response_data)
+}
 
-    let mut mount_options: HashMap<String, String> = HashMap::new();
-    mount_options.insert("backup-volfile-servers".into(),
-                         backup_servers.iter().join(",").to_string());
-    let quota_path = PathBuf::from(format!("/{}", id));
-    let quota_size: u64 = match state.statvfs(&quota_path) {
-        Ok(stat) => {
-            //This is in bytes.  We need to convert to GB
-            (stat.f_frsize * stat.f_blocks) / 1024 / 1024 / 1024
-        }
+// Every volume is a directory on the one replica 3 gluster volume
+fn volume_durability() -> Durability {
+    Durability { mount_type: Some(VolumeType::Replicate),
+                 replicate: Some(ReplicaDurability { replica: Some(3) }) }
+}
+
+// The quota on a volume in GB, as statvfs sees it through the quota
+fn volume_size(state: &Gluster, id: &VolumeId) -> u64 {
+    match state.statvfs(Path::new(id.as_str())) {
+        Ok(stat) => (stat.f_frsize * stat.f_blocks) / 1024 / 1024 / 1024,
         Err(e) => {
-            println!("statvfs error for {}: {:?}", *vol_name, e);
+            println!("statvfs error for {}: {:?}", id, e);
             0
         }
-    };
+    }
+}
+
+// How clients mount a volume.  The first host serves the volfile and the
+// rest are its backups
+fn volume_mount(hosts: Vec<String>, vol_name: &str, id: &VolumeId, name: &str) -> Mount {
+    let mut options: HashMap<String, String> = HashMap::new();
+    options.insert("backup-volfile-servers".into(), hosts.iter().join(","));
+    let device = format!("{server}:/{volume}/{id}/{name}",
+                         server = hosts.first().map_or("", |h| h.as_str()),
+                         volume = vol_name,
+                         id = id,
+                         name = name);
+    Mount { glusterfs: GlusterFsMount { hosts, device, options } }
+}
+
+// The peer files are enough to describe the cluster so carry on without
+// connection state if the gluster cli is unhappy
+fn peers_or_empty() -> Vec<Peer> {
+    peer_list().unwrap_or_else(|e| {
+                   println!("peer_list failed: {}", e);
+                   vec![]
+               })
+}
 
-    let response_data =
-        VolumeInfo { name: format!("{volume}/{id}/{name}",
-                                   volume = *vol_name,
-                                   id = id,
-                                   name = name),
-                     id: id.clone(),
-                     cluster: "cluster-test".into(),
-                     size: quota_size,
-                     durability: Durability { mount_type: Some(VolumeType::Replicate),
-                                              replicate:
-                                                  Some(ReplicaDurability { replica: Some(3) }) },
-                     snapshot: Snapshot { enable: Some(true), factor: Some(1.20) },
-                     mount: Mount { glusterfs: GlusterFsMount { hosts: backup_servers,
-                                                                device: format!(
-                    "{server}:/{volume}/{id}/{name}",
-                    server = peers[0].hostname,
-                    volume = *vol_name,
-                    id = id,
-                    name = name
-                ),
-                                                                options: mount_options } },
-                     bricks: vec![] };
-    println!("VolumeInfo: {}", serde_json::to_string(&response_data).map_err(|e| e.to_string())?);
-    let response = Response::build()
-        .header(ContentType::JSON)
-        .raw_header("X-Pending", "false")
-        .sized_body(Cursor::new(
-            serde_json::to_string(&response_data).map_err(|e| e.to_string())?,
-        )).finalize();
+// Describe the volume in /<id>/<name> the way heketi would
+fn volume_info(state: &Gluster,
+               vol_name: &str,
+               cluster_id: &str,
+               id: &VolumeId,
+               name: &str,
+               peers: &[Peer])
+               -> ApiResult<VolumeInfo> {
+    if peers.is_empty() {
+        return Err(ApiError::BackendUnavailable("gluster returned no peers".into()));
+    }
+    let hosts: Vec<String> = peers.iter().map(|ref p| p.hostname.clone()).collect();
+    let quota_size = volume_size(state, id);
+
+    // The directory the client mounts
+    let sub_dir = Path::new(id.as_str()).join(name);
+    let ownership = ownership::read(state, &sub_dir)?;
+
+    Ok(VolumeInfo { name: format!("{volume}/{id}/{name}",
+                                  volume = vol_name,
+                                  id = id,
+                                  name = name),
+                    id: id.to_string(),
+                    cluster: cluster_id.into(),
+                    size: quota_size,
+                    durability: volume_durability(),
+                    snapshot: Snapshot { enable: Some(true), factor: Some(1.20) },
+                    mount: volume_mount(hosts, vol_name, id, name),
+                    bricks: vec![],
+                    tags: tags::read(state, id)?,
+                    acl: acl::read(state, &sub_dir)?,
+                    uid: ownership.uid,
+                    gid: ownership.gid,
+                    mode: format!("{:04o}", ownership.mode),
+                    selinux_label: selinux::read(state, &sub_dir) })
+}
+
+fn volume_info_response<'a>(response_data: &VolumeInfo) -> ApiResult<Response<'a>> {
+    println!("VolumeInfo: {}", serde_json::to_string(response_data)?);
+    let response = Response::build().header(ContentType::JSON)
+                                    .raw_header("X-Pending", "false")
+                                    .sized_body(Cursor::new(serde_json::to_string(response_data)?))
+                                    .finalize();
     println!("response: {:#?}", response);
     Ok(response)
 }
 
-#[post("/volumes/<vol_name>/<id>/<name>/expand", format = "application/json", data = "<input>")]
+#[post("/volumes/<id>/tags", format = "application/json", data = "<input>")]
+fn set_volume_tags<'a>(_web_token: Jwt,
+                       id: Result<VolumeId, ApiError>,
+                       input: Json<tags::TagsChangeRequest>,
+                       live: State<'_, Arc<LiveConfig>>,
+                       locks: State<'_, Locks>,
+                       vol_name: State<'_, String>,
+                       state: State<'_, Arc<Gluster>>)
+                       -> ApiResult<Response<'a>> {
+    let id = id?;
+    let _lock = locks.volume(&live.current().lock, &state, &id, &format!("tags {}", id))?;
+    if !state.exists(&Path::new(id.as_str()))? {
+        return Err(ApiError::NotFound(format!("Unable to find volume {}", id)));
+    }
+    let new_tags = tags::apply(&tags::read(&state, &id)?, &input);
+    tags::validate(&new_tags)?;
+    println!("Setting tags on {} to {:?}", id, new_tags);
+    tags::write(&state, &id, &new_tags)?;
+
+    let peers = peer_list()?;
+    let name = get_subdir_name(&Path::new(id.as_str()), &state)?.unwrap_or_default();
+    let cluster_id = topology::cluster_id(&live.current().gluster, &vol_name)?;
+    let response_data = volume_info(&state, &vol_name, &cluster_id, &id, &name, &peers)?;
+    volume_info_response(&response_data)
+}
+
+#[post("/volumes/<id>/acl", format = "application/json", data = "<input>")]
+fn set_volume_acl<'a>(_web_token: Jwt,
+                      id: Result<VolumeId, ApiError>,
+                      input: Json<acl::Acl>,
+                      live: State<'_, Arc<LiveConfig>>,
+                      locks: State<'_, Locks>,
+                      vol_name: State<'_, String>,
+                      state: State<'_, Arc<Gluster>>)
+                      -> ApiResult<Response<'a>> {
+    let id = id?;
+    let _lock = locks.volume(&live.current().lock, &state, &id, &format!("acl {}", id))?;
+    let top_dir = Path::new(id.as_str());
+    let name = match get_subdir_name(&top_dir, &state)? {
+        Some(name) => name,
+        None => return Err(ApiError::NotFound(format!("Unable to find volume {}", id))),
+    };
+    acl::validate(&input)?;
+    // Replaces the whole ACL, an empty one removes it
+    let sub_dir = top_dir.join(&name);
+    println!("Setting ACL on {} to {:?}", sub_dir.display(), *input);
+    acl::apply_traverse(&state, &top_dir, &input, ownership::read(&state, &top_dir)?.mode)?;
+    acl::apply(&state, &sub_dir, &input, ownership::read(&state, &sub_dir)?.mode)?;
+
+    let peers = peer_list()?;
+    let cluster_id = topology::cluster_id(&live.current().gluster, &vol_name)?;
+    let response_data = volume_info(&state, &vol_name, &cluster_id, &id, &name, &peers)?;
+    volume_info_response(&response_data)
+}
+
+#[post("/volumes/<volume>/<id>/<name>/expand", format = "application/json", data = "<input>")]
 fn expand_volume<'a>(_web_token: Jwt,
-                     vol_name: String,
-                     id: String,
-                     name: String,
-                     input: Json<ExpandVolumeRequest>)
-                     -> Result<Response<'a>, String> {
+                     volume: Result<VolumeName, ApiError>,
+                     id: Result<VolumeId, ApiError>,
+                     name: Result<VolumeName, ApiError>,
+                     input: Json<ExpandVolumeRequest>,
+                     live: State<'_, Arc<LiveConfig>>,
+                     locks: State<'_, Locks>,
+                     vol_name: State<'_, String>,
+                     state: State<'_, Arc<Gluster>>)
+                     -> ApiResult<Response<'a>> {
+    let volume = volume?;
+    let id = id?;
+    let name = name?;
+    let config = live.current();
+    let _lock = locks.volume(&config.lock, &state, &id, &format!("expand {}", id))?;
+    let sub_dir = PathBuf::from(format!("{}/{}", id, name));
+    if volume.as_str() != vol_name.as_str() || !state.exists(&sub_dir)? {
+        return Err(ApiError::NotFound(format!("Unable to find volume {}/{}/{}",
+                                              volume, id, name)));
+    }
+    expand(&config, &state, &vol_name, &id, input.expand_size)
+}
+
+#[post("/volumes/<id>/expand", format = "application/json", data = "<input>")]
+fn expand_volume_by_id<'a>(_web_token: Jwt,
+                           id: Result<VolumeId, ApiError>,
+                           input: Json<ExpandVolumeRequest>,
+                           live: State<'_, Arc<LiveConfig>>,
+                           locks: State<'_, Locks>,
+                           vol_name: State<'_, String>,
+                           state: State<'_, Arc<Gluster>>)
+                           -> ApiResult<Response<'a>> {
+    let id = id?;
+    let config = live.current();
+    let _lock = locks.volume(&config.lock, &state, &id, &format!("expand {}", id))?;
+    if !state.exists(&Path::new(id.as_str()))? {
+        return Err(ApiError::NotFound(format!("Unable to find volume {}", id)));
+    }
+    expand(&config, &state, &vol_name, &id, input.expand_size)
+}
+
+// Grow a volume's quota by expand_size GB like heketi does
+fn expand<'a>(config: &config::Config,
+              state: &Gluster,
+              vol_name: &str,
+              id: &VolumeId,
+              expand_size: u64)
+              -> ApiResult<Response<'a>> {
+    if expand_size == 0 {
+        return Err(ApiError::InvalidInput("expand_size must be at least 1GB".into()));
+    }
+    let current = match quota::get_usage(&config.quota, state, vol_name, id)? {
+        Some(usage) => usage.hard_limit,
+        None => {
+            let msg = format!("Volume {} has no quota to expand", id);
+            return Err(ApiError::Conflict(msg));
+        }
+    };
+    let new_limit = current.checked_add(expand_size.saturating_mul(quota::GB))
+                           .ok_or_else(|| ApiError::InvalidInput("expand_size is too big".into()))?;
+    // max_size is whole GB so round the new limit down
+    quota::check_max_size(&config.quota, new_limit / quota::GB)?;
+    quota::check_growth(&config.quota, state, vol_name, current, new_limit)?;
+
+    println!("Expanding quota on {} from {} to {}", id, current, new_limit);
+    quota::set_limit(&config.quota, state, vol_name, id, new_limit)?;
+
+    let name = get_subdir_name(&Path::new(id.as_str()), state)?.unwrap_or_default();
     let mut response = Response::new();
     response.set_header(Location(format!("/volumes/{}/{}/{}", vol_name, id, name)));
     response.set_status(Status::Accepted);
+    Ok(response)
+}
 
-    // If this doesn't have a quota already it'll fail to remove
-    let quota_path = PathBuf::from(format!("/{}", id));
-    println!("Expanding quota on {} to {}",
-             quota_path.display(),
-             input.expand_size * 1024 * 1024 * 1024);
-    // input.expand_size needs to be converted to bytes from GB of input
-    volume_add_quota(
-        &vol_name,
-        &quota_path,
-        input.expand_size * 1024 * 1024 * 1024,
-    ).map_err(|e| e.to_string())?;
+#[post("/volumes/<id>/resize", format = "application/json", data = "<input>")]
+fn resize_volume<'a>(_web_token: Jwt,
+                     id: Result<VolumeId, ApiError>,
+                     input: Json<ResizeVolumeRequest>,
+                     live: State<'_, Arc<LiveConfig>>,
+                     locks: State<'_, Locks>,
+                     vol_name: State<'_, String>,
+                     state: State<'_, Arc<Gluster>>)
+                     -> ApiResult<Response<'a>> {
+    let id = id?;
+    let config = live.current();
+    let _lock = locks.volume(&config.lock, &state, &id, &format!("resize {}", id))?;
+    if !state.exists(&Path::new(id.as_str()))? {
+        return Err(ApiError::NotFound(format!("Unable to find volume {}", id)));
+    }
+    if input.size == 0 {
+        return Err(ApiError::InvalidInput("size must be at least 1GB".into()));
+    }
+    quota::check_max_size(&config.quota, input.size)?;
+
+    let new_limit = input.size * quota::GB;
+    match quota::get_usage(&config.quota, &state, &vol_name, &id)? {
+        Some(ref q) if new_limit < q.hard_limit => {
+            quota::check_shrink(q.used, new_limit, config.quota.shrink_margin)?;
+            println!("Shrinking quota on {} from {} to {}, {} used",
+                     id, q.hard_limit, new_limit, q.used);
+        }
+        Some(ref q) => {
+            quota::check_growth(&config.quota, &state, &vol_name, q.hard_limit, new_limit)?;
+            println!("Growing quota on {} from {} to {}", id, q.hard_limit, new_limit);
+        }
+        None => {
+            // Without a quota there's no accounting to say what's in use
+            let msg = format!("Volume {} has no quota so its usage is unknown", id);
+            return Err(ApiError::Conflict(msg));
+        }
+    }
+    quota::set_limit(&config.quota, &state, &vol_name, &id, new_limit)?;
 
+    let name = get_subdir_name(&Path::new(id.as_str()), &state)?.unwrap_or_default();
+    let mut response = Response::new();
+    response.set_header(Location(format!("/volumes/{}/{}/{}", *vol_name, id, name)));
+    response.set_status(Status::Accepted);
     Ok(response)
 }
 
 #[delete("/volumes/<vol_name>/<id>/<name>")]
 fn delete_volume<'a>(_web_token: Jwt,
-                     vol_name: String,
-                     id: String,
-                     name: String,
-                     state: State<'_, Gluster>)
-                     -> Result<Response<'a>, String> {
+                     vol_name: Result<VolumeName, ApiError>,
+                     id: Result<VolumeId, ApiError>,
+                     name: Result<VolumeName, ApiError>,
+                     live: State<'_, Arc<LiveConfig>>,
+                     locks: State<'_, Locks>,
+                     gluster_vol: State<'_, String>,
+                     state: State<'_, Arc<Gluster>>)
+                     -> ApiResult<Response<'a>> {
+    let vol_name = vol_name?;
+    let id = id?;
+    let name = name?;
     // Clients will keep calling this and we need to return 204 when it's finished
     // This works out well because rm -rf could take awhile.
     let mut response = Response::new();
@@ -725,28 +896,49 @@ fn delete_volume<'a>(_web_token: Jwt,
                                          name = name)));
 
     // Split this into the volume_name/volume_id and just delete the volume_id
+    delete(&live.current(), &state, &gluster_vol, &locks, &id)?;
+    Ok(response)
+}
+
+// Remove a volume along with its quota and gid.  Deleting one that's already
+// gone is fine
+fn delete(config: &config::Config,
+          state: &Gluster,
+          vol_name: &str,
+          locks: &Locks,
+          id: &VolumeId)
+          -> ApiResult<()> {
     println!("Deleting {}", id);
+    let _lock = locks.volume(&config.lock, state, id, &format!("delete {}", id))?;
+    if !state.exists(Path::new(id.as_str()))? {
+        return Ok(());
+    }
+    // The quota goes first, gluster can't find it once the directory is gone
+    quota::remove_limit(&config.quota, state, vol_name, id)?;
+    gids::release(state, id)?;
 
     // Delete the directory.
     // TODO: How can we background this and tell the client to come back later?
-    state.remove_dir_all(&Path::new(&id)).map_err(|e| e.to_string())?;
-
-    Ok(response)
+    state.remove_dir_all(Path::new(id.as_str()))?;
+    Ok(())
 }
 
 #[delete("/volumes/<vol_id>")]
 fn delete_volume_fallback<'a>(_web_token: Jwt,
-                              vol_id: String,
+                              vol_id: Result<VolumeId, ApiError>,
+                              live: State<'_, Arc<LiveConfig>>,
+                              locks: State<'_, Locks>,
                               vol_name: State<'_, String>,
-                              state: State<'_, Gluster>)
-                              -> Result<Response<'a>, String> {
+                              state: State<'_, Arc<Gluster>>)
+                              -> ApiResult<Response<'a>> {
+    let vol_id = vol_id?;
     // Clients will keep calling this and we need to return 204 when it's finished
     // This works out well because rm -rf could take awhile.
 
     let mut response = Response::new();
     // Open the top level dir and find the nested dir_name for the client to later query
     // There should only be 1 dir in this top level dir
-    let subdir_name = get_subdir_name(&Path::new(&vol_id), &state)?;
+    let subdir_name = get_subdir_name(&Path::new(vol_id.as_str()), &state)?;
     println!("delete subdir: {:?}", subdir_name);
 
     match subdir_name {
@@ -766,82 +958,293 @@ fn delete_volume_fallback<'a>(_web_token: Jwt,
                                          name = subdir_name.unwrap_or_else(|| "".into()),)));
 
     // Split this into the volume_name/volume_id and just delete the volume_id
-    println!("Deleting {}", vol_id);
-
-    // Delete the directory.
-    // TODO: How can we background this and tell the client to come back later?
-    state.remove_dir_all(&Path::new(&vol_id)).map_err(|e| e.to_string())?;
-
+    delete(&live.current(), &state, &vol_name, &locks, &vol_id)?;
     Ok(response)
 }
 
-#[get("/volumes")]
-fn list_volumes(_web_token: Jwt, state: State<'_, Gluster>) -> Result<Json<VolumeList>, String> {
-    let mut vol_list: Vec<String> = vec![];
-    let d = state.opendir(&Path::new("/")).map_err(|e| e.to_string())?;
-    let this = Path::new(".");
-    let parent = Path::new("..");
-    for dir_entry in d {
-        let dir_entry = dir_entry.map_err(|e| e.to_string())?;
-        // Skip the parent and current dir entries
-        if dir_entry.path == this || dir_entry.path == parent {
-            continue;
-        }
-        if let DT_DIR = dir_entry.file_type {
-            vol_list.push(format!("{}", dir_entry.path.display()).to_string());
+#[get("/volumes?<query..>")]
+fn list_volumes(_web_token: Jwt,
+                query: LenientForm<VolumeQuery>,
+                live: State<'_, Arc<LiveConfig>>,
+                vol_name: State<'_, String>,
+                state: State<'_, Arc<Gluster>>)
+                -> ApiResult<Json<VolumeList>> {
+    let config = live.current();
+    let filter = VolumeFilter::from_query(&query)?;
+    let cluster_id = topology::cluster_id(&config.gluster, &vol_name)?;
+
+    let mut ids = vec![];
+    // Every volume is in our one cluster
+    if filter.cluster.as_ref().map_or(true, |c| *c == cluster_id) {
+        for id in volume_ids(&state)? {
+            match filter.matches(&state, &id) {
+                Ok(true) => ids.push(id),
+                Ok(false) => {}
+                // Deleted while we were listing
+                Err(ApiError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
         }
     }
-    let volumes = VolumeList { volumes: vol_list };
-    println!("volume list: {:?}", volumes);
+    let (ids, next_offset) = filter.page(ids);
+
+    let volumes = if filter.details {
+        let peers = peer_list()?;
+        let mut volumes = vec![];
+        for id in ids {
+            let info = get_subdir_name(Path::new(id.as_str()), &state).and_then(|name| {
+                           let name = name.unwrap_or_default();
+                           volume_info(&state, &vol_name, &cluster_id, &id, &name, &peers)
+                       });
+            match info {
+                Ok(info) => volumes.push(VolumeEntry::Details(info)),
+                // Deleted while we were listing
+                Err(ApiError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        volumes
+    } else {
+        ids.into_iter().map(|id| VolumeEntry::Id(id.to_string())).collect()
+    };
+    let volumes = VolumeList { volumes, next_offset };
+    println!("volume list: {} volumes, next offset {:?}", volumes.volumes.len(), next_offset);
 
     Ok(Json(volumes))
 }
 
+#[derive(Debug, Serialize)]
+struct ReloadResponse {
+    changes: Vec<String>,
+    /// Changed settings that only a restart applies
+    not_applied: Vec<String>,
+}
+
+#[post("/admin/reload")]
+fn reload(_admin: Admin,
+          live: State<'_, Arc<LiveConfig>>,
+          state: State<'_, Arc<Gluster>>)
+          -> ApiResult<Json<ReloadResponse>> {
+    let (changes, not_applied) = reload_config(&live, &state).map_err(ApiError::Internal)?;
+    Ok(Json(ReloadResponse { changes, not_applied }))
+}
+
+#[derive(Debug, Serialize)]
+struct LeaderResponse {
+    instance: String,
+    is_leader: bool,
+    leader: Option<Lease>,
+}
+
+fn leader_response(leadership: &Leadership) -> LeaderResponse {
+    LeaderResponse { instance: leadership.instance().into(),
+                     is_leader: leadership.is_leader(),
+                     leader: leadership.leader() }
+}
+
+#[get("/admin/leader")]
+fn get_leader(_admin: Admin, leadership: State<'_, Arc<Leadership>>) -> Json<LeaderResponse> {
+    Json(leader_response(&leadership))
+}
+
+#[derive(Debug, Serialize)]
+struct QuotaCleanupResponse {
+    removed: Vec<String>,
+    failed: Vec<String>,
+}
+
+// Run a job only on the leader.  Other instances send the client on to the
+// leader when it has a url, and refuse otherwise
+fn leader_only<'a>(leadership: &Leadership,
+                   uri: &Origin<'_>,
+                   job: &str)
+                   -> ApiResult<Option<Response<'a>>> {
+    match leadership.require_leader(job) {
+        Ok(()) => Ok(None),
+        Err((_, Some(url))) => {
+            let mut response = Response::new();
+            response.set_status(Status::TemporaryRedirect);
+            response.set_header(Location(format!("{}{}", url.trim_end_matches('/'), uri)));
+            Ok(Some(response))
+        }
+        Err((e, None)) => Err(e),
+    }
+}
+
+#[post("/admin/quota/cleanup?<dry_run>")]
+fn cleanup_quotas_job<'a>(_admin: Admin,
+                          dry_run: Option<bool>,
+                          uri: &Origin<'_>,
+                          leadership: State<'_, Arc<Leadership>>,
+                          vol_name: State<'_, String>,
+                          state: State<'_, Arc<Gluster>>)
+                          -> ApiResult<Response<'a>> {
+    if let Some(response) = leader_only(&leadership, uri, "quota cleanup")? {
+        return Ok(response);
+    }
+    let (removed, failed) = quota::remove_stale(&state, &vol_name, dry_run.unwrap_or(false))?;
+    let strings = |paths: Vec<PathBuf>| paths.iter().map(|p| p.display().to_string()).collect();
+    let body = QuotaCleanupResponse { removed: strings(removed), failed: strings(failed) };
+    let response = Response::build().header(ContentType::JSON)
+                                    .sized_body(Cursor::new(serde_json::to_string(&body)?))
+                                    .finalize();
+    Ok(response)
+}
+
 #[get("/health")]
-fn healthy(state: State<'_, Gluster>) -> Result<String, String> {
+fn healthy(state: State<'_, Arc<Gluster>>) -> ApiResult<String> {
     // Panic and segfault the program if the gluster api connection is bad
     // systemd will then restart the program resulting in a fresh connection
     state.opendir(&Path::new("/")).unwrap();
     Ok("".to_string())
 }
 
+// Ready for requests when gluster answers.  Also says who the leader is
+#[get("/health/ready")]
+fn ready(state: State<'_, Arc<Gluster>>,
+         leadership: State<'_, Arc<Leadership>>)
+         -> ApiResult<Json<LeaderResponse>> {
+    state.statvfs(Path::new("/"))?;
+    Ok(Json(leader_response(&leadership)))
+}
+
 #[get("/version")]
 fn get_version() -> Json<Version> {
     let v = Version { version: crate_version!().to_string() };
     Json(v)
 }
 
+#[catch(400)]
+fn bad_request(_req: &Request<'_>) -> ApiError {
+    ApiError::InvalidInput("The request could not be understood".into())
+}
+
+#[catch(401)]
+fn unauthorized(req: &Request<'_>) -> ApiError {
+    auth::failure(req).unwrap_or(AuthError::Missing).into()
+}
+
+#[catch(403)]
+fn forbidden(req: &Request<'_>) -> ApiError {
+    auth::failure(req).unwrap_or_else(|| AuthError::Forbidden("not allowed".into())).into()
+}
+
+#[catch(404)]
+fn not_found(req: &Request<'_>) -> ApiError {
+    ApiError::NotFound(format!("No route for {}", req.uri()))
+}
+
+#[catch(422)]
+fn unprocessable_entity(_req: &Request<'_>) -> ApiError {
+    ApiError::InvalidInput("The request body is not valid for this route".into())
+}
+
 #[catch(500)]
-fn internal_error() -> &'static str { "Whoops! Looks like we messed up." }
+fn internal_error(req: &Request<'_>) -> ApiError {
+    // The Jwt guard fails with a 500 when auth is misconfigured
+    match auth::failure(req) {
+        Some(e) => e.into(),
+        None => ApiError::Internal("Unexpected server error".into()),
+    }
+}
 
-#[catch(400)]
-fn not_found(req: &Request<'_>) -> String {
-    format!("I couldn't find '{}'. Try something else?", req.uri())
-}
-
-fn rocket() -> rocket::Rocket {
-    rocket::ignite().mount("/",
-                           routes![add_device,
-                                   add_node,
-                                   create_cluster,
-                                   create_volume,
-                                   delete_cluster,
-                                   delete_device,
-                                   delete_node,
-                                   delete_volume,
-                                   delete_volume_fallback,
-                                   expand_volume,
-                                   get_cluster_info,
-                                   get_device_info,
-                                   get_node_info,
-                                   get_version,
-                                   get_volume_info,
-                                   get_volume_info_by_id,
-                                   healthy,
-                                   list_clusters,
-                                   list_volumes,])
-                    .register(catchers![internal_error, not_found])
-                    .manage(Mutex::new(HashMap::<String, String>::new()))
+// Reread the config file and secrets and apply whatever can be changed
+// without dropping the gfapi connection or the listening socket
+fn reload_config(live: &LiveConfig,
+                 gluster: &Gluster)
+                 -> Result<(Vec<String>, Vec<String>), String> {
+    let reload = live.reload().map_err(|e| {
+                                   println!("Config reload failed, keeping the old config: {}", e);
+                                   e.to_string()
+                               })?;
+    if reload.changes.is_empty() {
+        println!("Config reloaded, nothing changed");
+    }
+    for change in &reload.changes {
+        println!("Config reloaded: {}", change);
+    }
+    for setting in &reload.not_applied {
+        println!("WARNING: config reload did not apply {}.  Restart piragua for it", setting);
+    }
+    if reload.old.log.gluster_log != reload.new.log.gluster_log
+       || reload.old.log.gluster_level != reload.new.log.gluster_level
+    {
+        let level = reload.new.log.gluster_log_level()?;
+        gluster.set_logging(&reload.new.log.gluster_log, level).map_err(|e| e.to_string())?;
+    }
+    Ok((reload.changes, reload.not_applied))
+}
+
+fn rocket(live: Arc<LiveConfig>) -> Result<rocket::Rocket, String> {
+    let config = live.current();
+    let env = Environment::active().map_err(|e| e.to_string())?;
+    let log_level = LoggingLevel::from_str(&config.log.level)?;
+    let rocket_config = RocketConfig::build(env).address(config.server.address.clone())
+                                                .port(config.server.port)
+                                                .log_level(log_level)
+                                                .finalize()
+                                                .map_err(|e| e.to_string())?;
+
+    Ok(rocket::custom(rocket_config).mount("/",
+                                           routes![add_device,
+                                                   add_node,
+                                                   create_cluster,
+                                                   create_volume,
+                                                   delete_cluster,
+                                                   delete_device,
+                                                   delete_node,
+                                                   delete_volume,
+                                                   delete_volume_fallback,
+                                                   expand_volume,
+                                                   expand_volume_by_id,
+                                                   get_cluster_info,
+                                                   get_device_info,
+                                                   get_node_info,
+                                                   get_topology,
+                                                   get_version,
+                                                   get_volume_info,
+                                                   get_volume_info_by_id,
+                                                   cleanup_quotas_job,
+                                                   get_leader,
+                                                   healthy,
+                                                   ready,
+                                                   list_clusters,
+                                                   list_volumes,
+                                                   reload,
+                                                   resize_volume,
+                                                   set_volume_tags,
+                                                   set_volume_acl,])
+                                    .register(catchers![bad_request,
+                                                        forbidden,
+                                                        internal_error,
+                                                        not_found,
+                                                        unauthorized,
+                                                        unprocessable_entity])
+                                    .manage(Mutex::new(HashMap::<String, String>::new()))
+                                    .manage(Locks::new(&config.gluster.volume))
+                                    .attach(error::request_id_fairing())
+                                    .manage(live))
+}
+
+// Where to load the config from, including any command line overrides
+fn config_source(matches: &clap::ArgMatches<'_>) -> ConfigSource {
+    ConfigSource { path: PathBuf::from(matches.value_of("config").unwrap_or(DEFAULT_CONFIG_PATH)),
+                   // Only insist the file exists if someone asked for a specific one
+                   required: matches.occurrences_of("config") > 0,
+                   volume: matches.value_of("volume").map(|v| v.to_string()) }
+}
+
+// Reload the config whenever we get a SIGHUP
+fn watch_sighup(live: Arc<LiveConfig>, gluster: Arc<Gluster>) -> IOResult<()> {
+    let signals = signal_hook::iterator::Signals::new(&[signal_hook::SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            println!("SIGHUP received, reloading config");
+            // reload_config logs the failure and keeps the old config
+            let _ = reload_config(&live, &gluster);
+        }
+    });
+    Ok(())
 }
 
 fn main() {
@@ -849,32 +1252,94 @@ fn main() {
         App::new("piragua").version(crate_version!())
                            .author(crate_authors!())
                            .about("Gluster thin Kubernetes volumes")
+                           .arg(Arg::with_name("config").long("config")
+                                                        .short("c")
+                                                        .help("Path to the piragua config file")
+                                                        .default_value(DEFAULT_CONFIG_PATH)
+                                                        .global(true)
+                                                        .takes_value(true))
                            .arg(Arg::with_name("volume").long("volume")
-                                                        .help("The gluster volume to manage")
-                                                        .required(true)
+                                                        .help("The gluster volume to manage. \
+                                                               Overrides gluster.volume in the \
+                                                               config file")
+                                                        .global(true)
                                                         .takes_value(true))
+                           .subcommand(SubCommand::with_name("config")
+                                           .about("Inspect the piragua configuration")
+                                           .subcommand(SubCommand::with_name("check")
+                                                           .about("Validate the config file and \
+                                                                   exit")))
+                           .subcommands(cli::subcommands())
+                           .after_help("The volume, quota and reconcile subcommands work on the \
+                                        gluster volume directly.  There's no mode that goes \
+                                        through a running piragua over HTTP.")
                            .get_matches();
-    // This is safe.  clap enforces that this is required
-    let volname = matches.value_of("volume").unwrap();
 
-    println!("Connecting to: gluster vol {}", volname);
-    let gluster = match Gluster::connect(volname, "localhost", 24007) {
-        Ok(conn) => conn,
+    if let ("config", Some(config_matches)) = matches.subcommand() {
+        match config_source(config_matches).load() {
+            Ok(config) => {
+                println!("{} is valid. Managing gluster vol {} via {}:{}",
+                         config_matches.value_of("config").unwrap_or(DEFAULT_CONFIG_PATH),
+                         config.gluster.volume,
+                         config.gluster.host,
+                         config.gluster.port);
+                println!("warning: {} are only read at startup.  After changing them restart \
+                          piragua, a reload keeps the running values",
+                         STARTUP_ONLY);
+                return;
+            }
+            Err(e) => {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let source = config_source(&matches);
+    let config = match source.load() {
+        Ok(c) => c,
         Err(e) => {
-            println!("Failed to connect to gluster: {}.  Exiting", e.to_string());
-            return;
+            println!("Failed to load configuration: {}.  Exiting", e);
+            std::process::exit(1);
         }
     };
-    let gfapi_log = match env::var("GLUSTER_LOG") {
-        Ok(s) => s,
+    if let ("token", Some(token_matches)) = matches.subcommand() {
+        std::process::exit(cli::token(&config, token_matches));
+    }
+    let volname = config.gluster.volume.clone();
+    let live = Arc::new(LiveConfig::new(source, config));
+    let config = live.current();
+
+    println!("Connecting to: gluster vol {}", volname);
+    let gluster = match Gluster::connect(&volname, &config.gluster.host, config.gluster.port) {
+        Ok(conn) => Arc::new(conn),
         Err(e) => {
-            println!("getting environment variable GLUSTER_LOG failed: {:?}", e);
+            println!("Failed to connect to gluster: {}.  Exiting", e.to_string());
             return;
         }
     };
-    if let Err(e) = gluster.set_logging(Path::new(&gfapi_log), GlusterLogLevel::Warning) {
-        println!("setting gluster log to {} failed: {:?}", gfapi_log, e);
+    // validate() already checked the level so this can't fail
+    let gfapi_level = config.log.gluster_log_level().unwrap_or(GlusterLogLevel::Warning);
+    if let Err(e) = gluster.set_logging(&config.log.gluster_log, gfapi_level) {
+        println!("setting gluster log to {} failed: {:?}", config.log.gluster_log.display(), e);
     }
 
-    rocket().manage(gluster).manage(volname.to_string()).launch();
+    if let Some(code) = cli::run(&config, &gluster, &volname, &matches) {
+        std::process::exit(code);
+    }
+
+    if let Err(e) = watch_sighup(live.clone(), gluster.clone()) {
+        println!("Unable to listen for SIGHUP, config reloads are disabled: {}", e);
+    }
+    let leadership = Arc::new(Leadership::new());
+    leader::spawn(leadership.clone(), live.clone(), gluster.clone());
+
+    let server = match rocket(live) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Failed to configure the web server: {}.  Exiting", e);
+            std::process::exit(1);
+        }
+    };
+    server.manage(gluster).manage(volname).manage(leadership).launch();
 }
@@ -0,0 +1,156 @@
+//! Who owns a new volume's directories and the permissions on them.
+//!
+//! Each setting comes from the create request if it has one, then from the
+//! StorageClass' entry in volumes.classes and then from the volumes
+//! defaults.  A StorageClass is found by the issuer of its token, which is
+//! the restuser heketi clients are configured with.
+use std::path::Path;
+
+use gfapi_sys::gluster::Gluster;
+use libc::{mode_t, S_ISGID};
+
+use crate::{config::{parse_mode, ClassConfig, VolumeConfig},
+            error::{ApiError, ApiResult}};
+
+/// What a create request asked for
+#[derive(Clone, Debug, Default)]
+pub struct OwnershipRequest {
+    pub uid: Option<u32>,
+    pub gid: Option<u64>,
+    /// Octal, ie "2770"
+    pub mode: Option<String>,
+    pub setgid: Option<bool>,
+}
+
+/// Where a new volume's group comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Gid {
+    /// Leave it with root's group
+    None,
+    Fixed(u32),
+    /// The lowest unclaimed gid in this range
+    Allocate {
+        min: u32,
+        max: u32,
+    },
+}
+
+/// The owner and permissions to give a new volume
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Policy {
+    pub uid: u32,
+    pub gid: Gid,
+    /// Includes the setgid bit
+    pub mode: mode_t,
+}
+
+/// The owner, group and permissions of a volume directory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ownership {
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: mode_t,
+}
+
+fn class_gid(class: &ClassConfig) -> Option<Gid> {
+    match (class.gid, class.gid_min, class.gid_max) {
+        (Some(gid), ..) => Some(Gid::Fixed(gid)),
+        (None, Some(min), Some(max)) => Some(Gid::Allocate { min, max }),
+        _ => None,
+    }
+}
+
+/// Work out the policy for a create request from the issuer of `class`
+pub fn resolve(config: &VolumeConfig,
+               class: Option<&str>,
+               request: &OwnershipRequest)
+               -> ApiResult<Policy> {
+    let default_class = ClassConfig::default();
+    let class = class.and_then(|c| config.classes.get(c)).unwrap_or(&default_class);
+
+    let mode = match request.mode {
+        Some(ref mode) => {
+            let mode = parse_mode(mode).map_err(ApiError::InvalidInput)?;
+            if mode & 0o700 == 0 {
+                return Err(ApiError::InvalidInput("mode must give the owner some access".into()));
+            }
+            mode
+        }
+        None => class.mode.unwrap_or(config.default_mode),
+    };
+    let setgid = request.setgid.or(class.setgid).unwrap_or(config.setgid);
+
+    let gid = match request.gid {
+        Some(gid) if gid > u64::from(u32::max_value() - 1) => {
+            return Err(ApiError::InvalidInput(format!("gid {} is out of range", gid)));
+        }
+        Some(gid) => Gid::Fixed(gid as u32),
+        None => class_gid(class).unwrap_or_else(|| {
+                                    match (config.default_gid, config.gid_min, config.gid_max) {
+                                        (Some(gid), ..) => Gid::Fixed(gid),
+                                        (None, Some(min), Some(max)) => Gid::Allocate { min, max },
+                                        _ => Gid::None,
+                                    }
+                                }),
+    };
+
+    Ok(Policy { uid: request.uid.or(class.uid).unwrap_or(config.default_uid),
+                gid,
+                mode: if setgid { mode | S_ISGID } else { mode } })
+}
+
+/// Give a directory its owner and permissions.  chmod comes last because
+/// chown clears the setgid bit
+pub fn apply(gluster: &Gluster, path: &Path, ownership: &Ownership) -> ApiResult<()> {
+    gluster.chown(path, ownership.uid, ownership.gid)?;
+    gluster.chmod(path, ownership.mode)?;
+    Ok(())
+}
+
+/// The owner, group and permissions a directory has now
+pub fn read(gluster: &Gluster, path: &Path) -> ApiResult<Ownership> {
+    let stat = gluster.stat(path)?;
+    Ok(Ownership { uid: stat.st_uid, gid: stat.st_gid, mode: stat.st_mode & 0o7777 })
+}
+
+#[test]
+fn test_resolve() {
+    use crate::config::Config;
+
+    let config = Config::from_toml(
+                                   r#"
+[volumes]
+default_mode = "0570"
+gid_min = 2000
+gid_max = 2999
+
+[volumes.classes.openshift]
+uid = 1000
+mode = "0770"
+setgid = true
+
+[volumes.classes.fixed]
+gid = 5000
+"#,
+    ).unwrap();
+    let config = config.volumes;
+    let request = |uid, gid, mode: Option<&str>| OwnershipRequest { uid,
+                                                                    gid,
+                                                                    mode: mode.map(String::from),
+                                                                    setgid: None };
+
+    assert_eq!(resolve(&config, None, &request(None, None, None)),
+               Ok(Policy { uid: 0, gid: Gid::Allocate { min: 2000, max: 2999 }, mode: 0o570 }));
+    assert_eq!(resolve(&config, Some("openshift"), &request(None, None, None)),
+               Ok(Policy { uid: 1000, gid: Gid::Allocate { min: 2000, max: 2999 }, mode: 0o2770 }));
+    assert_eq!(resolve(&config, Some("fixed"), &request(Some(7), None, Some("0750"))),
+               Ok(Policy { uid: 7, gid: Gid::Fixed(5000), mode: 0o750 }));
+    assert_eq!(resolve(&config, Some("unknown"), &request(None, Some(2500), None)),
+               Ok(Policy { uid: 0, gid: Gid::Fixed(2500), mode: 0o570 }));
+    for bad in &[request(None, Some(1 << 32), None),
+                 request(None, None, Some("0070")),
+                 request(None, None, Some("rwx"))]
+    {
+        assert_eq!(resolve(&config, None, bad).map_err(|e| e.code()), Err("invalid_input"));
+    }
+}
@@ -0,0 +1,329 @@
+//! Quota limits on volumes and the checks made before changing them.
+//!
+//! Every volume's size is the gluster quota on its top directory.  With
+//! quota.native set limits are written and read through gfapi, which skips
+//! the gluster cli and glusterd's cluster wide lock.  The cli is used when
+//! gluster won't take the xattr or the limit can't be read back.  glusterd
+//! only knows about limits set through the cli: native ones are missing from
+//! its quota.conf, so `gluster volume quota list` leaves them out and a
+//! brick that's replaced or added doesn't get them back from glusterd.
+use std::{path::{Path, PathBuf},
+          str::FromStr};
+
+use gfapi_sys::gluster::Gluster;
+use gluster::volume::{quota_list, volume_add_quota, volume_remove_quota, Quota};
+use libc::statvfs;
+
+use crate::{config::QuotaConfig,
+            error::{ApiError, ApiResult},
+            volume::{volume_ids, VolumeId}};
+
+pub const GB: u64 = 1024 * 1024 * 1024;
+
+/// The xattr gluster keeps a directory's quota_limits_t in
+const LIMIT_XATTR: &str = "trusted.glusterfs.quota.limit-set";
+
+/// A volume's quota limit and how much of it is used, in bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Usage {
+    pub hard_limit: u64,
+    pub used: u64,
+}
+
+// Bytes as GB with a decimal for messages
+fn gb(bytes: u64) -> String { format!("{:.1}GB", bytes as f64 / GB as f64) }
+
+/// The quota path of a volume
+pub fn quota_path(id: &VolumeId) -> PathBuf { PathBuf::from(format!("/{}", id)) }
+
+/// The quota gluster has on a volume, if it has one
+pub fn get_quota(vol_name: &str, id: &VolumeId) -> ApiResult<Option<Quota>> {
+    let path = quota_path(id);
+    Ok(quota_list(vol_name)?.into_iter().find(|q| q.path == path))
+}
+
+// quota_limits_t: the hard limit in bytes then the soft limit percent, both
+// big endian.  A soft limit of -1 uses the volume's default
+fn encode_limit(hard_limit: u64) -> Vec<u8> {
+    let mut value = Vec::with_capacity(16);
+    value.extend_from_slice(&(hard_limit as i64).to_be_bytes());
+    value.extend_from_slice(&(-1i64).to_be_bytes());
+    value
+}
+
+/// Set a volume's quota limit in bytes
+pub fn set_limit(config: &QuotaConfig,
+                 gluster: &Gluster,
+                 vol_name: &str,
+                 id: &VolumeId,
+                 hard_limit: u64)
+                 -> ApiResult<()> {
+    let path = quota_path(id);
+    if config.native {
+        // Gluster refuses this with EPERM for untrusted clients and
+        // ENOTSUP when quota isn't enabled
+        match gluster.setxattr(&path, LIMIT_XATTR, &encode_limit(hard_limit), 0) {
+            Ok(()) => return Ok(()),
+            Err(e) => println!("Setting {} on {} failed, using the gluster cli: {}",
+                               LIMIT_XATTR,
+                               path.display(),
+                               e),
+        }
+    }
+    volume_add_quota(vol_name, &path, hard_limit)?;
+    Ok(())
+}
+
+/// Remove a volume's quota before the volume is deleted.  Volumes without a
+/// quota are fine
+pub fn remove_limit(config: &QuotaConfig,
+                    gluster: &Gluster,
+                    vol_name: &str,
+                    id: &VolumeId)
+                    -> ApiResult<()> {
+    let path = quota_path(id);
+    if !has_limit(gluster.getxattr(&path, LIMIT_XATTR).map_err(ApiError::from))? {
+        return Ok(());
+    }
+    // Limits set by the cli are also in glusterd's quota.conf, which only
+    // the cli can clean up.  If glusterd can't right now the volume is still
+    // deleted and `quota cleanup` removes the entry later
+    println!("Removing quota on {}", path.display());
+    if let Err(e) = volume_remove_quota(vol_name, &path) {
+        println!("Removing the quota on {} with the gluster cli failed: {}", path.display(), e);
+    }
+    if config.native {
+        match gluster.removexattr(&path, LIMIT_XATTR).map_err(ApiError::from) {
+            // No limit or no directory
+            Ok(()) | Err(ApiError::NotFound(_)) => {}
+            Err(e) => println!("Removing {} from {} failed: {}", LIMIT_XATTR, path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+// Whether a directory has a limit, from reading its limit-set xattr.  Every
+// limit is kept there, whether the cli or gfapi set it, and disabling quota
+// removes them, so it's missing on volumes without one
+fn has_limit(xattr: ApiResult<String>) -> ApiResult<bool> {
+    match xattr {
+        Ok(_) => Ok(true),
+        // ENODATA
+        Err(ApiError::NotFound(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Remove the quotas of volumes that no longer exist, which earlier versions
+/// left behind on delete.  Only quotas on volume directories are touched.
+/// Returns the paths cleaned up and the paths that couldn't be.
+pub fn remove_stale(gluster: &Gluster,
+                    vol_name: &str,
+                    dry_run: bool)
+                    -> ApiResult<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut removed = vec![];
+    let mut failed = vec![];
+    for quota in quota_list(vol_name)? {
+        let id = match quota.path.strip_prefix("/").ok().and_then(|p| p.to_str()) {
+            Some(p) => match VolumeId::from_str(p) {
+                Ok(id) => id,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        if gluster.exists(Path::new(id.as_str()))? {
+            continue;
+        }
+        if dry_run {
+            removed.push(quota.path);
+            continue;
+        }
+        match volume_remove_quota(vol_name, &quota.path) {
+            Ok(_) => removed.push(quota.path),
+            Err(e) => {
+                println!("Removing stale quota on {} failed: {}", quota.path.display(), e);
+                failed.push(quota.path);
+            }
+        }
+    }
+    Ok((removed, failed))
+}
+
+// With features.quota-deem-statfs on, the default since gluster 3.7, statfs
+// of a directory with a quota reports the limit as its size.  A directory
+// the same size as the volume root has no quota, or deem-statfs is off
+fn deemed_usage(dir: &statvfs, root: &statvfs) -> Option<Usage> {
+    if dir.f_blocks == 0 || dir.f_blocks * dir.f_frsize == root.f_blocks * root.f_frsize {
+        return None;
+    }
+    Some(Usage { hard_limit: dir.f_blocks * dir.f_frsize,
+                 used: dir.f_blocks.saturating_sub(dir.f_bfree) * dir.f_frsize })
+}
+
+/// A volume's quota limit and usage, if it has a quota
+pub fn get_usage(config: &QuotaConfig,
+                 gluster: &Gluster,
+                 vol_name: &str,
+                 id: &VolumeId)
+                 -> ApiResult<Option<Usage>> {
+    if config.native {
+        let root = gluster.statvfs(Path::new("/"))?;
+        if let Some(usage) = deemed_usage(&gluster.statvfs(&quota_path(id))?, &root) {
+            return Ok(Some(usage));
+        }
+    }
+    Ok(get_quota(vol_name, id)?.map(|q| Usage { hard_limit: q.hard_limit, used: q.used }))
+}
+
+// The quotas of every volume added up.  Limits set through gfapi aren't in
+// the cli's quota list so with quota.native every volume is looked at
+fn allocated(config: &QuotaConfig, gluster: &Gluster, vol_name: &str) -> ApiResult<u64> {
+    if !config.native {
+        let quotas = quota_list(vol_name)?;
+        return Ok(quotas.iter().fold(0u64, |sum, q| sum.saturating_add(q.hard_limit)));
+    }
+    let root = gluster.statvfs(Path::new("/"))?;
+    let mut sum = 0u64;
+    for id in volume_ids(gluster)? {
+        match gluster.statvfs(&quota_path(&id)).map(|dir| deemed_usage(&dir, &root)) {
+            Ok(Some(usage)) => sum = sum.saturating_add(usage.hard_limit),
+            Ok(None) => {}
+            // Deleted while we were adding up
+            Err(e) => match ApiError::from(e) {
+                ApiError::NotFound(_) => {}
+                e => return Err(e),
+            },
+        }
+    }
+    Ok(sum)
+}
+
+/// Check the gluster volume has room for a volume's quota to go from
+/// `current` to `new_limit` bytes
+pub fn check_growth(config: &QuotaConfig,
+                    gluster: &Gluster,
+                    vol_name: &str,
+                    current: u64,
+                    new_limit: u64)
+                    -> ApiResult<()> {
+    let stat = gluster.statvfs(Path::new("/"))?;
+    let capacity = stat.f_blocks * stat.f_frsize;
+    let free = stat.f_bavail * stat.f_frsize;
+    let increase = new_limit.saturating_sub(current);
+    if config.overcommit == 0 {
+        return check_capacity(config, 0, capacity, free, increase);
+    }
+    check_capacity(config, allocated(config, gluster, vol_name)?, capacity, free, increase)
+}
+
+/// Check a requested size in GB against quota.max_size
+pub fn check_max_size(config: &QuotaConfig, size: u64) -> ApiResult<()> {
+    if config.max_size != 0 && size > config.max_size {
+        let msg =
+            format!("Requested size {}GB is larger than the {}GB limit", size, config.max_size);
+        return Err(ApiError::InvalidInput(msg));
+    }
+    Ok(())
+}
+
+/// Refuse to shrink a volume to a limit that doesn't leave `margin` percent
+/// on top of what it already uses
+pub fn check_shrink(used: u64, new_limit: u64, margin: u64) -> ApiResult<()> {
+    let needed = used.saturating_add(used / 100 * margin + used % 100 * margin / 100);
+    if needed > new_limit {
+        let msg = format!("The volume uses {} and needs at least {} with the {}% margin, \
+                           so it can't shrink to {}",
+                          gb(used),
+                          gb(needed),
+                          margin,
+                          gb(new_limit));
+        return Err(ApiError::Conflict(msg));
+    }
+    Ok(())
+}
+
+/// Check the gluster volume can take `increase` more bytes of quota: the
+/// increase has to fit in the free space and, with quota.overcommit set, the
+/// quotas of every volume (`allocated`) can't add up to more than that share
+/// of the volume
+pub fn check_capacity(config: &QuotaConfig,
+                      allocated: u64,
+                      capacity: u64,
+                      free: u64,
+                      increase: u64)
+                      -> ApiResult<()> {
+    if increase > free {
+        let msg = format!("Growing by {} needs more than the {} free", gb(increase), gb(free));
+        return Err(ApiError::NoSpace(msg));
+    }
+    if config.overcommit != 0 {
+        let allowed = (capacity as u128 * config.overcommit as u128 / 100) as u64;
+        if allocated.saturating_add(increase) > allowed {
+            let msg = format!("Growing by {} would allocate more than the {} allowed by the \
+                               {}% overcommit, {} is already allocated",
+                              gb(increase),
+                              gb(allowed),
+                              config.overcommit,
+                              gb(allocated));
+            return Err(ApiError::NoSpace(msg));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_shrink() {
+    assert!(check_shrink(5 * GB, 1024 * GB, 10).is_ok());
+    assert!(check_shrink(5 * GB, 6 * GB, 10).is_ok());
+    assert!(check_shrink(5 * GB, 5 * GB, 0).is_ok());
+    assert_eq!(check_shrink(5 * GB, 5 * GB, 10).map_err(|e| e.code()), Err("conflict"));
+    assert_eq!(check_shrink(u64::max_value(), 5 * GB, 10).map_err(|e| e.code()), Err("conflict"));
+    assert!(check_shrink(0, GB, 10).is_ok());
+}
+
+#[test]
+fn test_check_capacity() {
+    let mut config = QuotaConfig::default();
+    let check = |config: &QuotaConfig, increase| {
+        check_capacity(config, 250 * GB, 200 * GB, 50 * GB, increase).map_err(|e| e.code())
+    };
+
+    assert_eq!(check(&config, 50 * GB), Ok(()));
+    assert_eq!(check(&config, 51 * GB), Err("no_space"));
+    config.overcommit = 150;
+    assert_eq!(check(&config, 50 * GB), Ok(()));
+    assert_eq!(check(&config, 51 * GB), Err("no_space"));
+    config.overcommit = 100;
+    assert_eq!(check(&config, GB), Err("no_space"));
+}
+
+#[test]
+fn test_native_limits() {
+    assert_eq!(encode_limit(GB),
+               vec![0, 0, 0, 0, 0x40, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+
+    let stat = |blocks, bfree| {
+        let mut s: statvfs = unsafe { std::mem::zeroed() };
+        s.f_frsize = 4096;
+        s.f_blocks = blocks;
+        s.f_bfree = bfree;
+        s
+    };
+    let root = stat(1 << 30, 1 << 29);
+    // 10GB limit with 1GB used
+    assert_eq!(deemed_usage(&stat(10 << 18, 9 << 18), &root),
+               Some(Usage { hard_limit: 10 * GB, used: GB }));
+    assert_eq!(deemed_usage(&stat(1 << 30, 1 << 29), &root), None);
+    assert_eq!(deemed_usage(&stat(0, 0), &root), None);
+}
+
+#[test]
+fn test_remove_limit_without_quota() {
+    use gfapi_sys::gluster::GlusterError;
+
+    let xattr = |msg: &str| Err(ApiError::from(GlusterError::new(msg.into())));
+    // Quota disabled, or never set on the volume
+    assert_eq!(has_limit(xattr("No data available (os error 61)")).ok(), Some(false));
+    assert_eq!(has_limit(Ok("\u{0}\u{0}".into())).ok(), Some(true));
+    assert!(has_limit(xattr("Transport endpoint is not connected (os error 107)")).is_err());
+}
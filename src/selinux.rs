@@ -0,0 +1,139 @@
+//! SELinux labels on new volumes.
+//!
+//! With SELinux enforcing, containers can only use files labelled for them,
+//! ie `system_u:object_r:container_file_t:s0`.  The label comes from the
+//! StorageClass' entry in volumes.classes or volumes.selinux_label, and a
+//! create request may add MCS categories to it so only its pod's containers
+//! can use the volume.
+use std::path::Path;
+
+use gfapi_sys::gluster::Gluster;
+
+use crate::{config::VolumeConfig,
+            error::{ApiError, ApiResult}};
+
+const SELINUX_XATTR: &str = "security.selinux";
+// The highest category in the default MCS policy
+const MAX_CATEGORY: u32 = 1023;
+
+/// Check a label has a user, role, type and level, ie
+/// "system_u:object_r:container_file_t:s0"
+pub fn check_label(label: &str) -> Result<(), String> {
+    let parts: Vec<&str> = label.splitn(4, ':').collect();
+    if parts.len() != 4 || parts.iter().any(|p| p.is_empty()) {
+        return Err(format!("SELinux label {:?} must be user:role:type:level", label));
+    }
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    if !parts[..3].iter().all(|p| p.chars().all(allowed))
+       || !parts[3].chars().all(|c| allowed(c) || ":,.-".contains(c))
+    {
+        return Err(format!("SELinux label {:?} has characters that aren't allowed", label));
+    }
+    Ok(())
+}
+
+// A single category is cN, a range cN.cM
+fn check_categories(categories: &str) -> ApiResult<()> {
+    let category =
+        |c: &str| c.starts_with('c') && c[1..].parse::<u32>().map_or(false, |n| n <= MAX_CATEGORY);
+    let valid = categories.split(',').all(|item| {
+                                         let mut range = item.splitn(2, '.');
+                                         range.next().map_or(false, category)
+                                         && range.next().map_or(true, category)
+                                     });
+    if !valid {
+        let msg = format!("SELinux categories {:?} must be like c1,c5 or c0.c255, up to c{}",
+                          categories, MAX_CATEGORY);
+        return Err(ApiError::InvalidInput(msg));
+    }
+    Ok(())
+}
+
+/// The label for a new volume from the issuer of `class`, with the
+/// categories the request asked for.  None if volumes aren't labelled.
+pub fn resolve(config: &VolumeConfig,
+               class: Option<&str>,
+               categories: Option<&str>)
+               -> ApiResult<Option<String>> {
+    let label = class.and_then(|c| config.classes.get(c))
+                     .and_then(|c| c.selinux_label.as_ref())
+                     .or_else(|| config.selinux_label.as_ref());
+    match (label, categories) {
+        (None, None) => Ok(None),
+        (None, Some(_)) => {
+            let msg = "SELinux categories were given but volumes aren't labelled, set \
+                       volumes.selinux_label";
+            Err(ApiError::InvalidInput(msg.into()))
+        }
+        (Some(label), None) => Ok(Some(label.clone())),
+        (Some(label), Some(categories)) => {
+            check_categories(categories)?;
+            // The categories follow the sensitivity, which can't have any
+            // already
+            if label.split(':').count() != 4 {
+                let msg = format!("SELinux label {:?} already has categories", label);
+                return Err(ApiError::InvalidInput(msg));
+            }
+            Ok(Some(format!("{}:{}", label, categories)))
+        }
+    }
+}
+
+/// Label a directory.  The value is NUL terminated like libselinux's
+pub fn apply(gluster: &Gluster, path: &Path, label: &str) -> ApiResult<()> {
+    let mut value = label.as_bytes().to_vec();
+    value.push(0);
+    gluster.setxattr(path, SELINUX_XATTR, &value, 0)?;
+    Ok(())
+}
+
+/// A directory's label, if it has one.  Volume info shouldn't fail because
+/// the bricks can't report labels, so errors only get logged.
+pub fn read(gluster: &Gluster, path: &Path) -> Option<String> {
+    match gluster.getxattr(path, SELINUX_XATTR) {
+        Ok(label) => Some(label.trim_end_matches('\0').to_string()),
+        Err(e) => match ApiError::from(e) {
+            ApiError::NotFound(_) => None,
+            e => {
+                println!("Reading the SELinux label of {} failed: {}", path.display(), e);
+                None
+            }
+        },
+    }
+}
+
+#[test]
+fn test_selinux_label() {
+    use crate::config::Config;
+
+    let config = Config::from_toml(
+                                   r#"
+[volumes]
+selinux_label = "system_u:object_r:container_file_t:s0"
+
+[volumes.classes.restricted]
+selinux_label = "system_u:object_r:container_file_t:s0:c1,c2"
+"#,
+    ).unwrap();
+    let config = config.volumes;
+
+    assert_eq!(resolve(&config, None, None),
+               Ok(Some("system_u:object_r:container_file_t:s0".into())));
+    assert_eq!(resolve(&config, Some("openshift"), Some("c5,c10.c20")),
+               Ok(Some("system_u:object_r:container_file_t:s0:c5,c10.c20".into())));
+    assert_eq!(resolve(&config, Some("restricted"), None),
+               Ok(Some("system_u:object_r:container_file_t:s0:c1,c2".into())));
+    for &(class, categories) in
+        &[(None, "c1024"), (None, "s0:c1"), (None, "c1,"), (Some("restricted"), "c5")]
+    {
+        assert_eq!(resolve(&config, class, Some(categories)).map_err(|e| e.code()),
+                   Err("invalid_input"));
+    }
+    assert_eq!(resolve(&VolumeConfig::default(), None, Some("c1")).map_err(|e| e.code()),
+               Err("invalid_input"));
+    assert_eq!(resolve(&VolumeConfig::default(), None, None), Ok(None));
+
+    assert!(check_label("system_u:object_r:container_file_t:s0-s0:c0.c1023").is_ok());
+    assert!(check_label("system_u:object_r:container_file_t").is_err());
+    assert!(check_label("system_u:object_r:container file_t:s0").is_err());
+}
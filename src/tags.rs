@@ -0,0 +1,152 @@
+//! Key/value tags on volumes, the way heketi tags nodes and devices.
+//!
+//! Tags are kept as JSON in an xattr on the volume's top directory so they
+//! live and die with the volume and are shared by every piragua instance.
+use std::{collections::BTreeMap, path::Path};
+
+use gfapi_sys::gluster::Gluster;
+
+use crate::{error::{ApiError, ApiResult},
+            volume::VolumeId};
+
+pub type Tags = BTreeMap<String, String>;
+
+const TAGS_XATTR: &str = "user.piragua.tags";
+const MAX_TAGS: usize = 32;
+const MAX_KEY_LEN: usize = 128;
+const MAX_VALUE_LEN: usize = 512;
+
+/// How a tag change request applies its tags, as in heketi's
+/// `POST /nodes/<id>/tags`
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum ChangeType {
+    /// Replace every tag
+    #[serde(rename = "set")]
+    Set,
+    /// Add or overwrite the given tags
+    #[serde(rename = "update")]
+    Update,
+    /// Remove the given keys.  Values are ignored
+    #[serde(rename = "delete")]
+    Delete,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagsChangeRequest {
+    pub change_type: ChangeType,
+    #[serde(default)]
+    pub tags: Tags,
+}
+
+fn valid_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || c == '/'
+}
+
+/// Check tags before they're stored
+pub fn validate(tags: &Tags) -> ApiResult<()> {
+    if tags.len() > MAX_TAGS {
+        return Err(ApiError::InvalidInput(format!("A volume can have at most {} tags", MAX_TAGS)));
+    }
+    for (key, value) in tags {
+        if key.is_empty() || key.len() > MAX_KEY_LEN || !key.chars().all(valid_key_char) {
+            let msg = format!("tag {:?} must be 1 to {} letters, numbers, '-', '_', '.' or '/'",
+                              key, MAX_KEY_LEN);
+            return Err(ApiError::InvalidInput(msg));
+        }
+        if value.len() > MAX_VALUE_LEN {
+            let msg = format!("tag {:?} is longer than {} bytes", key, MAX_VALUE_LEN);
+            return Err(ApiError::InvalidInput(msg));
+        }
+    }
+    Ok(())
+}
+
+/// The tags after applying a change
+pub fn apply(current: &Tags, change: &TagsChangeRequest) -> Tags {
+    match change.change_type {
+        ChangeType::Set => change.tags.clone(),
+        ChangeType::Update => {
+            let mut tags = current.clone();
+            tags.extend(change.tags.clone());
+            tags
+        }
+        ChangeType::Delete => current.iter()
+                                     .filter(|(k, _)| !change.tags.contains_key(*k))
+                                     .map(|(k, v)| (k.clone(), v.clone()))
+                                     .collect(),
+    }
+}
+
+/// A volume's tags.  Volumes that were never tagged have none
+pub fn read(gluster: &Gluster, id: &VolumeId) -> ApiResult<Tags> {
+    match gluster.getxattr(Path::new(id.as_str()), TAGS_XATTR) {
+        Ok(data) => Ok(serde_json::from_str(data.trim_end_matches('\0'))?),
+        // ENODATA, the xattr was never set
+        Err(e) => match ApiError::from(e) {
+            ApiError::NotFound(_) => Ok(Tags::new()),
+            e => Err(e),
+        },
+    }
+}
+
+pub fn write(gluster: &Gluster, id: &VolumeId, tags: &Tags) -> ApiResult<()> {
+    let top_dir = Path::new(id.as_str());
+    if tags.is_empty() {
+        return match gluster.removexattr(top_dir, TAGS_XATTR).map_err(ApiError::from) {
+            Ok(()) | Err(ApiError::NotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        };
+    }
+    gluster.setxattr(top_dir, TAGS_XATTR, serde_json::to_string(tags)?.as_bytes(), 0)?;
+    Ok(())
+}
+
+/// Parse a `tag` filter: comma separated `key` or `key:value` terms, all of
+/// which have to match
+pub fn parse_filter(filter: &str) -> ApiResult<Vec<(String, Option<String>)>> {
+    let mut terms = vec![];
+    for term in filter.split(',') {
+        let mut parts = term.splitn(2, ':');
+        let key = parts.next().unwrap_or("");
+        if key.is_empty() || !key.chars().all(valid_key_char) {
+            return Err(ApiError::InvalidInput(format!("tag filter {:?} is not valid", term)));
+        }
+        terms.push((key.to_string(), parts.next().map(|v| v.to_string())));
+    }
+    Ok(terms)
+}
+
+pub fn matches(tags: &Tags, filter: &[(String, Option<String>)]) -> bool {
+    filter.iter().all(|(key, value)| match (tags.get(key), value) {
+                     (Some(v), Some(want)) => v == want,
+                     (Some(_), None) => true,
+                     (None, _) => false,
+                 })
+}
+
+#[test]
+fn test_tags() {
+    let tags = |pairs: &[(&str, &str)]| -> Tags {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    };
+    let current = tags(&[("team", "storage"), ("cost", "42")]);
+    let change = |change_type, pairs: &[(&str, &str)]| {
+        apply(&current, &TagsChangeRequest { change_type, tags: tags(pairs) })
+    };
+
+    assert_eq!(change(ChangeType::Set, &[("a", "1")]), tags(&[("a", "1")]));
+    assert_eq!(change(ChangeType::Update, &[("cost", "7")]),
+               tags(&[("team", "storage"), ("cost", "7")]));
+    assert_eq!(change(ChangeType::Delete, &[("cost", "")]), tags(&[("team", "storage")]));
+
+    assert!(validate(&current).is_ok());
+    assert!(validate(&tags(&[("", "x")])).is_err());
+    assert!(validate(&tags(&[("a b", "x")])).is_err());
+    assert!(validate(&tags(&[("a", &"x".repeat(MAX_VALUE_LEN + 1))])).is_err());
+
+    let filter = parse_filter("team:storage,cost").unwrap();
+    assert!(matches(&current, &filter));
+    assert!(!matches(&tags(&[("team", "storage")]), &filter));
+    assert!(!matches(&current, &parse_filter("team:web").unwrap()));
+    assert!(parse_filter("team,,cost").is_err());
+}
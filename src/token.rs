@@ -0,0 +1,216 @@
+//! Making and checking tokens the way heketi clients do, so operators don't
+//! have to.
+use std::{fs, path::Path};
+
+use chrono::{DateTime, TimeZone, Utc};
+use jsonwebtoken::{dangerous_insecure_decode, decode_header, encode, Algorithm, EncodingKey,
+                   Header};
+use ring::digest;
+
+use crate::auth::{self, AuthError, Claims, KeySet, DEFAULT_KEY_ID};
+
+/// heketi's query string hash: the sha256 of "METHOD&path" in hex
+pub fn qsh(method: &str, path: &str) -> String {
+    let claim = format!("{}&{}", method.to_uppercase(), path);
+    digest::digest(&digest::SHA256, claim.as_bytes()).as_ref()
+                                                     .iter()
+                                                     .map(|b| format!("{:02x}", b))
+                                                     .collect()
+}
+
+/// A configured key and what to sign with it
+pub struct Signer {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+}
+
+/// Sign with the configured key `id`.  A shared secret is read from the
+/// config, a public key needs the PEM private key it pairs with.
+pub fn signer(keys: &KeySet, id: &str, private_key: Option<&Path>) -> Result<Signer, String> {
+    let key = keys.get(id).ok_or_else(|| {
+                               format!("there's no key {:?} in auth.secret, auth.keys or \
+                                         auth.jwks_file",
+                                       id)
+                           })?;
+    if !key.is_active(&Utc::now()) {
+        return Err(format!("key {:?} expired, piragua rejects tokens signed with it", id));
+    }
+    let encoding = match (key.secret(), private_key) {
+        (Some(secret), None) => EncodingKey::from_secret(secret),
+        (Some(_), Some(_)) => {
+            return Err(format!("key {:?} is a shared secret, it doesn't need a private key", id));
+        }
+        (None, Some(path)) => {
+            let pem = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            match key.algorithm {
+                Algorithm::ES256 | Algorithm::ES384 => EncodingKey::from_ec_pem(&pem),
+                _ => EncodingKey::from_rsa_pem(&pem),
+            }.map_err(|e| format!("{}: {}", path.display(), e))?
+        }
+        (None, None) => {
+            return Err(format!("key {:?} is a {:?} public key, give the private key it pairs \
+                                with",
+                               id, key.algorithm));
+        }
+    };
+    // heketi clients don't name the key, the default one is tried first
+    let kid = if id == DEFAULT_KEY_ID { None } else { Some(id.to_string()) };
+    Ok(Signer { kid, algorithm: key.algorithm, encoding })
+}
+
+/// A token from `iss` for one request, valid for `lifetime` seconds from
+/// `now`
+pub fn generate(signer: &Signer,
+                iss: &str,
+                method: &str,
+                path: &str,
+                now: &DateTime<Utc>,
+                lifetime: u64)
+                -> Result<String, String> {
+    let iat = now.timestamp() as u64;
+    let claims = Claims { iss: iss.into(), iat, exp: iat + lifetime, qsh: qsh(method, path) };
+    let header = Header { kid: signer.kid.clone(), ..Header::new(signer.algorithm) };
+    encode(&header, &claims, &signer.encoding).map_err(|e| e.to_string())
+}
+
+// A unix time and how far it is from now
+fn when(time: u64, now: &DateTime<Utc>) -> String {
+    let seconds = time as i64 - now.timestamp();
+    let relative =
+        if seconds < 0 { format!("{}s ago", -seconds) } else { format!("in {}s", seconds) };
+    format!("{} ({})",
+            Utc.timestamp_opt(time as i64, 0).single().map(|t| t.to_rfc3339()).unwrap_or_default(),
+            relative)
+}
+
+/// Decode a token and say whether the Jwt guard accepts it, and if not
+/// why.  With `request`, a method and path, the qsh claim is checked too.
+pub fn explain(keys: &KeySet,
+               token: &str,
+               request: Option<(&str, &str)>,
+               now: &DateTime<Utc>)
+               -> (bool, Vec<String>) {
+    let mut lines = vec![];
+    let header = match decode_header(token) {
+        Ok(header) => header,
+        Err(e) => return (false, vec![format!("rejected: this isn't a JWT: {}", e)]),
+    };
+    let kid = header.kid.as_ref().map(|k| k.as_str());
+    lines.push(format!("algorithm {:?}, key {}", header.alg, kid.unwrap_or("not named")));
+    match dangerous_insecure_decode::<Claims>(token) {
+        Ok(data) => {
+            let claims = data.claims;
+            lines.push(format!("issuer {}", claims.iss));
+            lines.push(format!("issued {}", when(claims.iat, now)));
+            lines.push(format!("expires {}", when(claims.exp, now)));
+            match request {
+                Some((method, path)) if claims.qsh == qsh(method, path) => {
+                    lines.push(format!("qsh matches {} {}", method.to_uppercase(), path))
+                }
+                // The guard doesn't check it, but heketi does
+                Some((method, path)) => {
+                    lines.push(format!("qsh {} is for another request than {} {}.  piragua \
+                                        accepts it anyway, heketi wouldn't",
+                                       claims.qsh,
+                                       method.to_uppercase(),
+                                       path))
+                }
+                None => lines.push(format!("qsh {}", claims.qsh)),
+            }
+        }
+        Err(e) => lines.push(format!("the claims can't be read: {}", e)),
+    }
+
+    let error = match auth::verify(keys, token, now) {
+        Ok(jwt) => {
+            lines.push(format!("accepted: signed by key {:?}", jwt.key_id));
+            return (true, lines);
+        }
+        Err(e) => e,
+    };
+    lines.push(format!("rejected: {}", error));
+    match error {
+        AuthError::UnknownKey => match kid.map(|kid| (kid, keys.get(kid))) {
+            Some((kid, None)) => lines.push(format!("no key {:?} is configured", kid)),
+            Some((kid, Some(key))) if !key.is_active(now) => {
+                lines.push(format!("key {:?} expired {}",
+                                   kid,
+                                   key.expires.map(|e| e.to_rfc3339()).unwrap_or_default()))
+            }
+            Some((kid, Some(key))) => {
+                lines.push(format!("key {:?} only checks {:?} tokens", kid, key.algorithm))
+            }
+            None => {
+                let configured = keys.iter()
+                                     .filter(|k| k.is_active(now))
+                                     .map(|k| format!("{:?} {:?}", k.id, k.algorithm))
+                                     .collect::<Vec<_>>()
+                                     .join(", ");
+                lines.push(format!("no active key checks {:?} tokens, there's {}",
+                                   header.alg, configured))
+            }
+        },
+        AuthError::Expired => {
+            lines.push(format!("auth.leeway allows {}s after it expires", keys.leeway))
+        }
+        AuthError::InvalidSignature => {
+            let tried = keys.candidates(kid, header.alg, now)
+                            .iter()
+                            .map(|k| format!("{:?}", k.id))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+            lines.push(format!("it wasn't signed by any of the keys tried: {}", tried))
+        }
+        _ => {}
+    }
+    (false, lines)
+}
+
+#[test]
+fn test_qsh() {
+    assert_eq!(qsh("GET", "/volumes"),
+               "c162acc9024272120bacfff7709c932cc252337d0a30fa15b025010066662bea");
+    assert_eq!(qsh("get", "/volumes"), qsh("GET", "/volumes"));
+}
+
+#[test]
+fn test_generate_and_explain() {
+    use crate::config::{AuthConfig, KeyConfig};
+
+    let auth = AuthConfig { secret: Some("c3VwZXJfc2VjcmV0".into()),
+                            keys: vec![KeyConfig { id: "other".into(),
+                                                   secret: Some("b3RoZXI=".into()),
+                                                   ..Default::default() }],
+                            ..Default::default() };
+    let keys = KeySet::load(&auth).unwrap();
+    let now = Utc::now();
+    let token = |id, now: &DateTime<Utc>| {
+        generate(&signer(&keys, id, None).unwrap(), "admin", "get", "/volumes", now, 600).unwrap()
+    };
+
+    let (ok, lines) = explain(&keys, &token("default", &now), Some(("GET", "/volumes")), &now);
+    assert!(ok, "{:?}", lines);
+    assert!(lines.iter().any(|l| l.starts_with("qsh matches")));
+    let (ok, lines) = explain(&keys, &token("other", &now), Some(("DELETE", "/volumes")), &now);
+    assert!(ok, "{:?}", lines);
+    assert!(lines.iter().any(|l| l.contains("another request")));
+    assert_eq!(lines.last().unwrap(), "accepted: signed by key \"other\"");
+
+    let an_hour_ago = now - chrono::Duration::hours(1);
+    let (ok, lines) = explain(&keys, &token("default", &an_hour_ago), None, &now);
+    assert!(!ok);
+    assert!(lines.iter().any(|l| l == "rejected: JWT token has expired"), "{:?}", lines);
+
+    // Signed with a secret piragua doesn't know
+    let forged = Signer { kid: None,
+                          algorithm: Algorithm::HS256,
+                          encoding: EncodingKey::from_secret(b"not_the_secret") };
+    let forged = generate(&forged, "admin", "GET", "/volumes", &now, 600).unwrap();
+    let (ok, lines) = explain(&keys, &forged, None, &now);
+    assert!(!ok);
+    assert!(lines.last().unwrap().ends_with("\"default\", \"other\""), "{:?}", lines);
+
+    assert!(!explain(&keys, "not.a.jwt", None, &now).0);
+    assert!(signer(&keys, "missing", None).is_err());
+}
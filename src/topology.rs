@@ -0,0 +1,543 @@
+//! heketi's view of the cluster: nodes, the devices under their bricks and
+//! the volumes carved out of the gluster volume.
+//!
+//! piragua doesn't manage devices so everything here is derived from what
+//! glusterd already knows about the peers and the bricks of the managed
+//! volume.
+use std::{collections::HashMap,
+          ffi::{CStr, CString},
+          fs, mem,
+          net::{IpAddr, Ipv4Addr, Ipv6Addr},
+          os::unix::ffi::OsStrExt,
+          path::{Path, PathBuf},
+          ptr, str};
+
+use gfapi_sys::gluster::Gluster;
+use gluster::{get_local_ip,
+              peer::{Peer, State as PeerState}};
+use ring::digest;
+use uuid::Uuid;
+
+use crate::{config::{GlusterConfig, NodesConfig},
+            error::{ApiError, ApiResult},
+            get_subdir_name,
+            glusterd::{get_bricks, get_local_uuid, get_peer_info, get_peer_uuids, get_volume_id,
+                       BrickInfo},
+            volume::volume_ids,
+            volume_durability, volume_mount, volume_size, Brick, DeviceInfo, Durability,
+            ManagedHosts, Mount, NodeInfoResponse, Snapshot, Storage};
+
+#[derive(Debug, Serialize)]
+pub(crate) struct TopologyInfo {
+    pub(crate) clusters: Vec<ClusterTopology>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ClusterTopology {
+    pub(crate) id: String,
+    pub(crate) nodes: Vec<NodeInfoResponse>,
+    pub(crate) volumes: Vec<TopologyVolume>,
+    /// piragua only hands out file volumes
+    pub(crate) block: bool,
+    pub(crate) file: bool,
+}
+
+/// A volume in heketi's topology
+#[derive(Debug, Serialize)]
+pub(crate) struct TopologyVolume {
+    pub(crate) name: String,
+    pub(crate) id: String,
+    pub(crate) cluster: String,
+    pub(crate) size: u64,
+    pub(crate) durability: Durability,
+    pub(crate) snapshot: Snapshot,
+    pub(crate) mount: Mount,
+    /// Always empty, the bricks belong to the gluster volume
+    pub(crate) bricks: Vec<Brick>,
+}
+
+/// An id that stays the same for the same inputs across restarts and
+/// instances.  Formatted like heketi's ids.
+pub(crate) fn stable_id(parts: &[&str]) -> String {
+    let hash = digest::digest(&digest::SHA256, parts.join("\0").as_bytes());
+    hash.as_ref()[..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A line of /proc/self/mountinfo
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct MountInfo {
+    pub(crate) mount_point: PathBuf,
+    /// What is mounted, ie /dev/mapper/vg-brick1
+    pub(crate) source: String,
+}
+
+// mountinfo escapes spaces, tabs, newlines and backslashes as octal
+fn unescape_mount(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let octal =
+                str::from_utf8(&bytes[i + 1..i + 4]).ok()
+                                                    .and_then(|o| u8::from_str_radix(o, 8).ok());
+            if let Some(c) = octal {
+                out.push(c);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Parse the contents of /proc/self/mountinfo, skipping lines that don't
+/// make sense
+pub(crate) fn parse_mountinfo(data: &str) -> Vec<MountInfo> {
+    let mut mounts = vec![];
+    for line in data.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        // Optional fields end at the "-" separator
+        let sep = match fields.iter().position(|f| *f == "-") {
+            Some(sep) if sep >= 6 && fields.len() > sep + 2 => sep,
+            _ => continue,
+        };
+        mounts.push(MountInfo { mount_point: PathBuf::from(unescape_mount(fields[4])),
+                                source: unescape_mount(fields[sep + 2]) });
+    }
+    mounts
+}
+
+/// The mount `path` lives on.  Later mounts hide earlier ones at the same
+/// place so the last, longest match wins.
+pub(crate) fn find_mount<'a>(mounts: &'a [MountInfo], path: &Path) -> Option<&'a MountInfo> {
+    mounts.iter()
+          .filter(|m| path.starts_with(&m.mount_point))
+          .max_by_key(|m| m.mount_point.components().count())
+}
+
+// The mount point of a brick.  glusterd records where the brick sits
+// below the mount in mount_dir.
+fn brick_mount(brick: &BrickInfo) -> PathBuf {
+    if let Some(ref mount_dir) = brick.mount_dir {
+        let relative = mount_dir.strip_prefix("/").unwrap_or(mount_dir);
+        let path = brick.path.to_string_lossy();
+        let suffix = format!("/{}", relative.display());
+        if path.ends_with(&suffix) && path.len() > suffix.len() {
+            return PathBuf::from(&path[..path.len() - suffix.len()]);
+        }
+    }
+    brick.path.clone()
+}
+
+/// What a brick sits on.  On this machine that's the source of its mount,
+/// elsewhere the block device glusterd recorded or failing that the brick's
+/// mount point.
+pub(crate) fn brick_device(brick: &BrickInfo, mounts: Option<&[MountInfo]>) -> PathBuf {
+    let local =
+        mounts.and_then(|m| find_mount(m, &brick.path)).filter(|m| m.source.starts_with('/'));
+    match local {
+        Some(mount) => PathBuf::from(&mount.source),
+        None => brick.device_path.clone().unwrap_or_else(|| brick_mount(brick)),
+    }
+}
+
+/// Group a node's bricks by the device they're on.  `mounts` is only given
+/// for this machine, where the devices can be looked up and measured.
+///
+/// Device ids are made from the node and the brick's mount point, both of
+/// which glusterd copies to every peer, so every piragua instance hands out
+/// the same id for a device.
+pub(crate) fn node_devices(node_id: &Uuid,
+                           bricks: &[&BrickInfo],
+                           mounts: Option<&[MountInfo]>)
+                           -> Vec<DeviceInfo> {
+    let node = node_id.to_hyphenated().to_string();
+    let mut devices: Vec<DeviceInfo> = vec![];
+    for brick in bricks {
+        let device_id = stable_id(&[&node, &brick_mount(brick).to_string_lossy()]);
+        let brick_id = match brick.brick_id {
+            Some(ref id) => id.clone(),
+            None => stable_id(&[&node, &brick.path.to_string_lossy()]),
+        };
+        let storage = match mounts {
+            Some(_) => local_storage(&brick.path),
+            None => None,
+        };
+        let brick_info = Brick { id: brick_id,
+                                 path: brick.path.clone(),
+                                 size: storage.as_ref().map_or(0, |s| s.total),
+                                 node: node.clone(),
+                                 device: device_id.clone() };
+        match devices.iter_mut().find(|d| d.id == device_id) {
+            Some(device) => device.bricks.push(brick_info),
+            None => devices.push(DeviceInfo { name: brick_device(brick, mounts),
+                                              storage: storage.unwrap_or(Storage { total: 0,
+                                                                                   free: 0,
+                                                                                   used: 0 }),
+                                              id: device_id,
+                                              state: "online".into(),
+                                              bricks: vec![brick_info] }),
+        }
+    }
+    devices
+}
+
+/// The name this machine knows itself by
+pub fn local_hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok().filter(|h| !h.is_empty())
+}
+
+// The name the resolver gives this machine, usually its FQDN
+fn canonical_name(host: &str) -> Option<String> {
+    let c_host = CString::new(host).ok()?;
+    let mut hints: libc::addrinfo = unsafe { mem::zeroed() };
+    hints.ai_flags = libc::AI_CANONNAME;
+    let mut res: *mut libc::addrinfo = ptr::null_mut();
+    if unsafe { libc::getaddrinfo(c_host.as_ptr(), ptr::null(), &hints, &mut res) } != 0 {
+        return None;
+    }
+    let name = unsafe {
+        match (*res).ai_canonname {
+            p if p.is_null() => None,
+            p => CStr::from_ptr(p).to_str().ok().map(String::from),
+        }
+    };
+    unsafe { libc::freeaddrinfo(res) };
+    name
+}
+
+// Whether a brick could be recorded under an address.  Loopback and link
+// local addresses aren't reachable from the other peers
+fn is_peer_address(address: &IpAddr) -> bool {
+    match address {
+        IpAddr::V4(v4) => !v4.is_loopback() && !v4.is_link_local(),
+        IpAddr::V6(v6) => !v6.is_loopback() && v6.segments()[0] & 0xffc0 != 0xfe80,
+    }
+}
+
+// Every address on this machine's interfaces
+fn local_addresses() -> Vec<IpAddr> {
+    let mut ifaddrs: *mut libc::ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        println!("unable to list local addresses: {}", std::io::Error::last_os_error());
+        return vec![];
+    }
+    let mut addresses = vec![];
+    let mut next = ifaddrs;
+    while !next.is_null() {
+        let ifaddr = unsafe { &*next };
+        next = ifaddr.ifa_next;
+        if ifaddr.ifa_addr.is_null() {
+            continue;
+        }
+        let address = match i32::from(unsafe { (*ifaddr.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let sin = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr))
+            }
+            _ => continue,
+        };
+        addresses.push(address);
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    addresses
+}
+
+// The hostnames this machine is reported with, and every name its bricks
+// may be recorded under: those, its FQDN and all of its addresses
+fn local_names() -> (Vec<String>, Vec<String>) {
+    let ip = match get_local_ip() {
+        Ok(ip) => Some(ip.to_string()),
+        Err(e) => {
+            println!("unable to find the local ip: {}", e);
+            None
+        }
+    };
+    let host = local_hostname();
+    let fqdn = host.as_ref().and_then(|host| canonical_name(host));
+    merge_names(ip, host, fqdn, &local_addresses())
+}
+
+// local_names from what was found, each name once
+fn merge_names(ip: Option<String>,
+               host: Option<String>,
+               fqdn: Option<String>,
+               addresses: &[IpAddr])
+               -> (Vec<String>, Vec<String>) {
+    let mut hostnames: Vec<String> = vec![];
+    for name in ip.into_iter().chain(host).chain(fqdn) {
+        if !hostnames.contains(&name) {
+            hostnames.push(name);
+        }
+    }
+    let mut names = hostnames.clone();
+    for address in addresses.iter().filter(|a| is_peer_address(a)) {
+        let address = address.to_string();
+        if !names.contains(&address) {
+            names.push(address);
+        }
+    }
+    (hostnames, names)
+}
+
+/// Capacity of the filesystem holding `path` on this machine, in KiB like
+/// heketi reports it
+pub(crate) fn local_storage(path: &Path) -> Option<Storage> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let kib = |blocks: libc::fsblkcnt_t| blocks as u64 * stat.f_frsize as u64 / 1024;
+    Some(Storage { total: kib(stat.f_blocks),
+                   free: kib(stat.f_bavail),
+                   used: kib(stat.f_blocks - stat.f_bfree) })
+}
+
+/// Every node in the trusted pool with its devices
+pub(crate) fn get_nodes(state_dir: &Path,
+                        vol_name: &str,
+                        cluster_id: &str,
+                        zones: &NodesConfig,
+                        peers: &[Peer])
+                        -> ApiResult<Vec<NodeInfoResponse>> {
+    let bricks = get_bricks(state_dir, vol_name)?;
+    let gluster_peers: HashMap<Uuid, &Peer> = peers.iter().map(|p| (p.uuid, p)).collect();
+    let mut response = vec![];
+
+    let local_uuid = get_local_uuid(state_dir)?;
+    let mounts = match fs::read_to_string("/proc/self/mountinfo") {
+        Ok(data) => Some(parse_mountinfo(&data)),
+        Err(e) => {
+            println!("unable to read /proc/self/mountinfo: {}", e);
+            None
+        }
+    };
+    // Each node's id, hostnames, the names its bricks may be under and state
+    let mut nodes: Vec<(Uuid, Vec<String>, Vec<String>, &'static str)> = vec![];
+    if let Some(local) = local_uuid {
+        let (hostnames, names) = local_names();
+        nodes.push((local, hostnames, names, "online"));
+    }
+    for uuid in get_peer_uuids(state_dir)? {
+        // One bad peer file shouldn't hide the rest of the cluster
+        let peer = match get_peer_info(state_dir, &uuid) {
+            Ok(peer) => peer,
+            Err(e) => {
+                println!("skipping peer {}: {}", uuid, e);
+                continue;
+            }
+        };
+        let state = match gluster_peers.get(&uuid).map(|p| &p.status) {
+            // In the cluster but glusterd can't reach it right now
+            Some(PeerState::Disconnected) if peer.node_state() == "online" => "offline",
+            _ => peer.node_state(),
+        };
+        nodes.push((uuid, peer.hostnames.clone(), peer.hostnames, state));
+    }
+
+    for (id, hostnames, names, state) in nodes {
+        let node_bricks: Vec<&BrickInfo> =
+            bricks.iter().filter(|b| names.contains(&b.hostname)).collect();
+        // Only bricks on this machine can be looked up and measured
+        let devices = if Some(id) == local_uuid {
+            node_devices(&id, &node_bricks, mounts.as_ref().map(|m| m.as_slice()))
+        } else {
+            node_devices(&id, &node_bricks, None)
+        };
+        let zone = zones.zone(&id.to_hyphenated().to_string(), &hostnames);
+        response.push(NodeInfoResponse { zone,
+                                         hostnames: ManagedHosts { // Everyone manages themselves
+                                                                   manage: hostnames.clone(),
+                                                                   storage: hostnames },
+                                         cluster: cluster_id.into(),
+                                         id,
+                                         state: state.into(),
+                                         devices });
+    }
+    Ok(response)
+}
+
+/// The id heketi knows our one cluster by.  gluster.cluster_id wins,
+/// otherwise it's the volume-id of the gluster volume, which every glusterd
+/// agrees on and which survives restarts.
+pub(crate) fn cluster_id(config: &GlusterConfig, vol_name: &str) -> ApiResult<String> {
+    if let Some(ref id) = config.cluster_id {
+        return Ok(id.clone());
+    }
+    match get_volume_id(&config.state_dir, vol_name) {
+        // heketi ids are 32 hex characters
+        Ok(Some(id)) => Ok(id.to_simple().to_string()),
+        Ok(None) => {
+            Err(ApiError::BackendUnavailable(format!("glusterd has no volume-id for {}", vol_name)))
+        }
+        Err(e) => {
+            let msg = format!("Unable to read the volume-id of {}: {}", vol_name, e);
+            Err(ApiError::BackendUnavailable(msg))
+        }
+    }
+}
+
+/// Every volume directory at the top of the gluster volume.  Only the name
+/// and size are looked up for each; `GET /volumes/<id>` has the rest.
+/// `hosts` are the servers clients mount from.
+pub(crate) fn get_volumes(gluster: &Gluster,
+                          vol_name: &str,
+                          cluster_id: &str,
+                          hosts: &[String])
+                          -> ApiResult<Vec<TopologyVolume>> {
+    let mut volumes = vec![];
+    for id in volume_ids(gluster)? {
+        let name = match get_subdir_name(Path::new(id.as_str()), gluster) {
+            Ok(Some(name)) => name,
+            // Deleted since it was listed
+            Ok(None) | Err(ApiError::NotFound(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        volumes.push(TopologyVolume { name: format!("{}/{}/{}", vol_name, id, name),
+                                      id: id.to_string(),
+                                      cluster: cluster_id.into(),
+                                      size: volume_size(gluster, &id),
+                                      durability: volume_durability(),
+                                      snapshot: Snapshot { enable: Some(true),
+                                                           factor: Some(1.20) },
+                                      mount: volume_mount(hosts.to_vec(), vol_name, &id, &name),
+                                      bricks: vec![] });
+    }
+    Ok(volumes)
+}
+
+/// Find a device on any node by its id
+pub(crate) fn get_device(state_dir: &Path,
+                         vol_name: &str,
+                         cluster_id: &str,
+                         zones: &NodesConfig,
+                         peers: &[Peer],
+                         device_id: &str)
+                         -> ApiResult<DeviceInfo> {
+    get_nodes(state_dir, vol_name, cluster_id, zones, peers)?
+        .into_iter()
+        .flat_map(|n| n.devices)
+        .find(|d| d.id == device_id)
+        .ok_or_else(|| ApiError::NotFound(format!("Unable to find device {}", device_id)))
+}
+
+pub(crate) fn get_topology(state_dir: &Path,
+                           gluster: &Gluster,
+                           vol_name: &str,
+                           cluster_id: &str,
+                           zones: &NodesConfig,
+                           peers: &[Peer])
+                           -> ApiResult<TopologyInfo> {
+    let nodes = get_nodes(state_dir, vol_name, cluster_id, zones, peers)?;
+    // Mount from the nodes glusterd knows rather than the ones the cli
+    // answered with, which may be none
+    let hosts: Vec<String> =
+        nodes.iter().filter_map(|n| n.hostnames.storage.first().cloned()).collect();
+    let volumes = get_volumes(gluster, vol_name, cluster_id, &hosts)?;
+    let cluster =
+        ClusterTopology { id: cluster_id.into(), nodes, volumes, block: false, file: true };
+    Ok(TopologyInfo { clusters: vec![cluster] })
+}
+
+#[test]
+fn test_node_devices() {
+    use std::str::FromStr;
+
+    let brick = |path: &str, mount_dir: &str, device: Option<&str>| {
+        BrickInfo { hostname: "10.0.0.1".into(),
+                    path: PathBuf::from(path),
+                    device_path: device.map(PathBuf::from),
+                    mount_dir: Some(PathBuf::from(mount_dir)),
+                    brick_id: None }
+    };
+    let bricks = vec![brick("/bricks/b1/vol1/brick", "/vol1/brick", Some("/dev/sdb")),
+                      brick("/bricks/b2/brick", "/brick", None),
+                      brick("/bricks/b1/vol2/brick", "/vol2/brick", Some("/dev/sdb"))];
+    let node = Uuid::from_str("5e0b3f4a-7a0c-4c55-9a53-0e7a9e4f6c11").unwrap();
+    let devices = node_devices(&node, &bricks.iter().collect::<Vec<_>>(), None);
+
+    assert_eq!(devices.len(), 2);
+    assert_eq!(devices[0].name, PathBuf::from("/dev/sdb"));
+    assert_eq!(devices[0].bricks.len(), 2);
+    assert_eq!(devices[1].name, PathBuf::from("/bricks/b2"));
+    // The same device on the same node always gets the same id, whether or
+    // not it can be looked up
+    let mounts = parse_mountinfo("97 1 253:2 / /bricks/b1 rw - xfs /dev/mapper/vg-b1 rw");
+    let local = node_devices(&node, &[&bricks[0]], Some(&mounts));
+    assert_eq!(local[0].id, devices[0].id);
+    assert_eq!(local[0].name, PathBuf::from("/dev/mapper/vg-b1"));
+    assert_eq!(devices[0].id.len(), 32);
+    assert_eq!(devices[0].bricks[0].device, devices[0].id);
+}
+
+#[test]
+fn test_mountinfo() {
+    let mounts = parse_mountinfo(
+                                 "22 1 253:0 / / rw,relatime shared:1 - xfs /dev/mapper/root rw\n\
+         97 22 253:2 / /bricks/b1 rw,noatime shared:40 - xfs /dev/mapper/vg-b1 rw\n\
+         98 22 253:3 / /bricks/b10 rw,noatime - xfs /dev/mapper/vg-b10 rw\n\
+         99 22 0:50 / /mnt/with\\040space rw - tmpfs tmpfs rw\n\
+         broken line\n",
+    );
+    assert_eq!(mounts.len(), 4);
+    assert_eq!(mounts[3].mount_point, PathBuf::from("/mnt/with space"));
+    let source = |p: &str| find_mount(&mounts, Path::new(p)).map(|m| m.source.clone());
+    assert_eq!(source("/bricks/b1/vol1/brick"), Some("/dev/mapper/vg-b1".into()));
+    assert_eq!(source("/bricks/b10/brick"), Some("/dev/mapper/vg-b10".into()));
+    assert_eq!(source("/bricks/b100"), Some("/dev/mapper/root".into()));
+}
+
+#[test]
+fn test_local_names() {
+    let addresses: Vec<IpAddr> = ["127.0.0.1",
+                                  "10.0.0.1",
+                                  "169.254.0.5",
+                                  "::1",
+                                  "fe80::1",
+                                  "fd00::1",
+                                  "10.0.0.2"].iter()
+                                             .map(|a| a.parse().unwrap())
+                                             .collect();
+    let (hostnames, names) = merge_names(Some("10.0.0.1".into()),
+                                         Some("gluster-1".into()),
+                                         Some("gluster-1.example.com".into()),
+                                         &addresses);
+    assert_eq!(hostnames, vec!["10.0.0.1", "gluster-1", "gluster-1.example.com"]);
+    assert_eq!(names,
+               vec!["10.0.0.1", "gluster-1", "gluster-1.example.com", "fd00::1", "10.0.0.2"]);
+
+    // The resolver had nothing better than the short name
+    let (hostnames, names) =
+        merge_names(None, Some("gluster-1".into()), Some("gluster-1".into()), &[]);
+    assert_eq!(hostnames, vec!["gluster-1"]);
+    assert_eq!(names, hostnames);
+}
+
+#[test]
+fn test_cluster_id() {
+    let state_dir = std::env::temp_dir().join(format!("piragua-{}", Uuid::new_v4()));
+    fs::create_dir_all(state_dir.join("vols/gv0")).unwrap();
+    let info = "type=2\nvolume-id=7D1F6E0A-22A3-4E8B-9C3B-3F1B5B7C2E90\nbrick-0=a:-bricks-b1\n";
+    fs::write(state_dir.join("vols/gv0/info"), info).unwrap();
+    let mut config = GlusterConfig { state_dir: state_dir.clone(), ..Default::default() };
+
+    assert_eq!(cluster_id(&config, "gv0"), Ok("7d1f6e0a22a34e8b9c3b3f1b5b7c2e90".into()));
+    assert_eq!(cluster_id(&config, "gv1").map_err(|e| e.code()), Err("backend_unavailable"));
+    config.cluster_id = Some("prod-1".into());
+    assert_eq!(cluster_id(&config, "gv1"), Ok("prod-1".into()));
+    fs::remove_dir_all(state_dir).unwrap();
+}
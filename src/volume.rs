@@ -0,0 +1,366 @@
+//! Validated volume ids and names taken from URLs and requests.
+//!
+//! Every volume lives in `/<id>/<name>` on the gluster volume, so these are
+//! the only things a client can use to build a path.  Ids must be UUIDs and
+//! names may only use letters, numbers, `-` and `_`, which keeps `..`, the
+//! volume root and gluster's `.trashcan` and `.glusterfs` directories out of
+//! reach.  The same rule decides which top level directories are listed as
+//! volumes.
+use std::{fmt, path::Path, str::FromStr};
+
+use chrono::{DateTime, TimeZone, Utc};
+use gfapi_sys::gluster::Gluster;
+use libc::DT_DIR;
+use rocket::{http::RawStr, request::FromParam};
+use uuid::Uuid;
+
+use crate::{error::{ApiError, ApiResult},
+            get_subdir_name, tags};
+
+/// Longest volume name we'll create a directory for
+const MAX_NAME_LEN: usize = 128;
+
+/// When the volume was created, in unix seconds, on its top directory
+const CREATED_XATTR: &str = "user.piragua.created";
+
+/// piragua's own state on the gluster volume.  It isn't a volume id so it's
+/// never listed or handed out as a volume
+pub const STATE_DIR: &str = ".piragua";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VolumeId(String);
+
+impl VolumeId {
+    pub fn new_v4() -> VolumeId { VolumeId(Uuid::new_v4().to_hyphenated().to_string()) }
+
+    pub fn as_str(&self) -> &str { &self.0 }
+}
+
+impl FromStr for VolumeId {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<VolumeId, ApiError> {
+        // Only accept the hyphenated form so one volume has one id
+        let not_uuid = || ApiError::InvalidInput(format!("volume id {:?} is not a UUID", s));
+        if s.len() != 36 {
+            return Err(not_uuid());
+        }
+        let id = Uuid::from_str(s).map_err(|_| not_uuid())?;
+        if id.is_nil() {
+            return Err(ApiError::InvalidInput("the nil UUID is not a volume id".into()));
+        }
+        Ok(VolumeId(id.to_hyphenated().to_string()))
+    }
+}
+
+impl fmt::Display for VolumeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{}", self.0) }
+}
+
+impl<'a> FromParam<'a> for VolumeId {
+    type Error = ApiError;
+
+    fn from_param(param: &'a RawStr) -> Result<VolumeId, ApiError> {
+        let decoded = param.percent_decode().map_err(|e| ApiError::InvalidInput(e.to_string()))?;
+        VolumeId::from_str(&decoded)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VolumeName(String);
+
+impl VolumeName {
+    pub fn as_str(&self) -> &str { &self.0 }
+}
+
+// Returns true if this char is invalid
+fn invalid_chars(c: char) -> bool { !(c.is_alphabetic() || c.is_numeric() || c == '-' || c == '_') }
+
+impl FromStr for VolumeName {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<VolumeName, ApiError> {
+        if s.is_empty() || s.len() > MAX_NAME_LEN {
+            let msg = format!("volume name must be 1 to {} characters long", MAX_NAME_LEN);
+            return Err(ApiError::InvalidInput(msg));
+        }
+        if s.chars().any(invalid_chars) {
+            println!("Invalid characters detected in name");
+            let msg = "Only numbers, letters, '-' or '_' are allowed in the volume name";
+            return Err(ApiError::InvalidInput(msg.into()));
+        }
+        Ok(VolumeName(s.to_string()))
+    }
+}
+
+impl fmt::Display for VolumeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{}", self.0) }
+}
+
+impl<'a> FromParam<'a> for VolumeName {
+    type Error = ApiError;
+
+    fn from_param(param: &'a RawStr) -> Result<VolumeName, ApiError> {
+        let decoded = param.percent_decode().map_err(|e| ApiError::InvalidInput(e.to_string()))?;
+        VolumeName::from_str(&decoded)
+    }
+}
+
+/// The ids of every volume on the gluster volume, sorted so pages are stable.
+/// Files, `.`, `..`, `.trashcan`, `.glusterfs` and any other directory that
+/// isn't named after a volume id are left out.
+pub fn volume_ids(gluster: &Gluster) -> ApiResult<Vec<VolumeId>> {
+    let mut ids = vec![];
+    for dir_entry in gluster.opendir(Path::new("/"))? {
+        let dir_entry = dir_entry?;
+        if dir_entry.file_type != DT_DIR {
+            continue;
+        }
+        if let Some(Ok(id)) = dir_entry.path.to_str().map(VolumeId::from_str) {
+            ids.push(id);
+        }
+    }
+    ids.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(ids)
+}
+
+/// The query string of `GET /volumes`.  Everything is taken as a string so a
+/// bad value gets a 400 explaining it rather than a 404.
+#[derive(Debug, Default, FromForm)]
+pub struct VolumeQuery {
+    /// Only volumes whose name starts with this
+    prefix: Option<String>,
+    gid: Option<String>,
+    cluster: Option<String>,
+    /// Comma separated `key` or `key:value` tags the volume must have
+    tag: Option<String>,
+    /// RFC 3339 times
+    created_after: Option<String>,
+    created_before: Option<String>,
+    /// Return the info of each volume instead of its id
+    details: Option<String>,
+    offset: Option<String>,
+    limit: Option<String>,
+}
+
+/// A checked VolumeQuery
+#[derive(Debug, Default, PartialEq)]
+pub struct VolumeFilter {
+    pub prefix: Option<String>,
+    pub gid: Option<u32>,
+    pub cluster: Option<String>,
+    pub tags: Vec<(String, Option<String>)>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub details: bool,
+    pub offset: usize,
+    /// No limit if None
+    pub limit: Option<usize>,
+}
+
+fn parse_field<T: FromStr>(field: &str, value: &Option<String>) -> ApiResult<Option<T>> {
+    match *value {
+        Some(ref v) => {
+            v.parse()
+             .map(Some)
+             .map_err(|_| ApiError::InvalidInput(format!("{} {:?} is not valid", field, v)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn parse_time(field: &str, value: &Option<String>) -> ApiResult<Option<DateTime<Utc>>> {
+    match *value {
+        Some(ref v) => match DateTime::parse_from_rfc3339(v) {
+            Ok(t) => Ok(Some(t.with_timezone(&Utc))),
+            Err(e) => {
+                let msg = format!("{} {:?} is not an RFC 3339 time: {}", field, v, e);
+                Err(ApiError::InvalidInput(msg))
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+impl VolumeFilter {
+    pub fn from_query(query: &VolumeQuery) -> ApiResult<VolumeFilter> {
+        let limit = parse_field("limit", &query.limit)?;
+        if limit == Some(0) {
+            return Err(ApiError::InvalidInput("limit must be at least 1".into()));
+        }
+        Ok(VolumeFilter { prefix: query.prefix.clone(),
+                          gid: parse_field("gid", &query.gid)?,
+                          cluster: query.cluster.clone(),
+                          tags: match query.tag {
+                              Some(ref filter) => tags::parse_filter(filter)?,
+                              None => vec![],
+                          },
+                          created_after: parse_time("created_after", &query.created_after)?,
+                          created_before: parse_time("created_before", &query.created_before)?,
+                          details: parse_field("details", &query.details)?.unwrap_or(false),
+                          offset: parse_field("offset", &query.offset)?.unwrap_or(0),
+                          limit })
+    }
+
+    /// Check one volume against the filters.  Only looks the volume up if a
+    /// filter needs it, which keeps plain listings of big clusters cheap.
+    pub fn matches(&self, gluster: &Gluster, id: &VolumeId) -> ApiResult<bool> {
+        let top_dir = Path::new(id.as_str());
+        if let Some(ref prefix) = self.prefix {
+            let name = get_subdir_name(top_dir, gluster)?.unwrap_or_default();
+            if !name.starts_with(prefix.as_str()) {
+                return Ok(false);
+            }
+        }
+        if !self.tags.is_empty() && !tags::matches(&tags::read(gluster, id)?, &self.tags) {
+            return Ok(false);
+        }
+        if self.gid.is_some() || self.created_after.is_some() || self.created_before.is_some() {
+            let stat = gluster.stat(top_dir)?;
+            let created = match created(gluster, id)? {
+                Some(created) => Some(created),
+                // Made before the creation time was recorded.  Nothing is
+                // added to or removed from the top dir after the volume dir
+                // is made in it, so its mtime is close
+                None => Utc.timestamp_opt(stat.st_mtime as i64, 0).single(),
+            };
+            if self.gid.map_or(false, |gid| gid != stat.st_gid)
+               || self.created_after.map_or(false, |t| created.map_or(true, |c| c < t))
+               || self.created_before.map_or(false, |t| created.map_or(true, |c| c >= t))
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The page of `ids` asked for and the offset of the next page if there
+    /// is one
+    pub fn page<T>(&self, mut ids: Vec<T>) -> (Vec<T>, Option<usize>) {
+        let start = self.offset.min(ids.len());
+        let end = match self.limit {
+            Some(limit) => start.saturating_add(limit).min(ids.len()),
+            None => ids.len(),
+        };
+        let next = if end < ids.len() { Some(end) } else { None };
+        ids.truncate(end);
+        (ids.split_off(start), next)
+    }
+}
+
+fn parse_created(data: &str) -> Option<DateTime<Utc>> {
+    let seconds = data.trim_end_matches('\0').parse().ok()?;
+    Utc.timestamp_opt(seconds, 0).single()
+}
+
+/// When a volume was created, if that was recorded
+pub fn created(gluster: &Gluster, id: &VolumeId) -> ApiResult<Option<DateTime<Utc>>> {
+    match gluster.getxattr(Path::new(id.as_str()), CREATED_XATTR) {
+        Ok(data) => Ok(parse_created(&data)),
+        // ENODATA, the volume is older than the xattr
+        Err(e) => match ApiError::from(e) {
+            ApiError::NotFound(_) => Ok(None),
+            e => Err(e),
+        },
+    }
+}
+
+/// Record when a volume was created
+pub fn set_created(gluster: &Gluster, id: &VolumeId, now: &DateTime<Utc>) -> ApiResult<()> {
+    let value = now.timestamp().to_string();
+    gluster.setxattr(Path::new(id.as_str()), CREATED_XATTR, value.as_bytes(), 0)?;
+    Ok(())
+}
+
+#[test]
+fn test_valid_chars() {
+    let vol_name = "it_works-0";
+    assert_eq!(vol_name.chars().any(|c| invalid_chars(c)), false);
+
+    let vol_name = "it_fails!&";
+    assert_eq!(vol_name.chars().any(|c| invalid_chars(c)), true);
+}
+
+#[test]
+fn test_volume_params() {
+    let id = |s: &str| VolumeId::from_param(RawStr::from_str(s)).map(|id| id.to_string());
+    let name = |s: &str| VolumeName::from_param(RawStr::from_str(s)).map(|n| n.to_string());
+
+    assert_eq!(id("5E0B3F4A-7A0C-4C55-9A53-0E7A9E4F6C11"),
+               Ok("5e0b3f4a-7a0c-4c55-9a53-0e7a9e4f6c11".into()));
+    assert_eq!(name("vol_1-a"), Ok("vol_1-a".into()));
+    for bad in
+        &["", ".", "..", "%2e%2e", "..%2f..", "/", "%2F", ".trashcan", ".glusterfs", "a/b", "a%00b"]
+    {
+        assert!(id(bad).is_err(), "id {:?}", bad);
+        assert!(name(bad).is_err(), "name {:?}", bad);
+    }
+    // Valid names but not ids
+    assert!(id("00000000-0000-0000-0000-000000000000").is_err());
+    assert!(id("5e0b3f4a7a0c4c559a530e7a9e4f6c11").is_err());
+    assert!(name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+}
+
+#[test]
+fn test_bad_param_is_400() {
+    use rocket::{http::Status, local::Client};
+
+    // Routes take their ids and names as Results so a bad one is answered
+    // with why, rather than forwarded to a 404
+    #[get("/volumes/<id>/<name>")]
+    fn show(id: Result<VolumeId, ApiError>,
+            name: Result<VolumeName, ApiError>)
+            -> ApiResult<String> {
+        Ok(format!("{}/{}", id?, name?))
+    }
+
+    let client = Client::new(rocket::ignite().mount("/", routes![show])).unwrap();
+    let good = "5e0b3f4a-7a0c-4c55-9a53-0e7a9e4f6c11";
+    let mut res = client.get(format!("/volumes/{}/vol_1", good)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(res.body_string(), Some(format!("{}/vol_1", good)));
+    for uri in &["/volumes/not-a-uuid/vol_1".to_string(), format!("/volumes/{}/..", good)] {
+        let mut res = client.get(uri.as_str()).dispatch();
+        assert_eq!(res.status(), Status::BadRequest, "{}", uri);
+        let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        assert_eq!(body["code"], "invalid_input", "{}", uri);
+    }
+}
+
+#[test]
+fn test_volume_filter() {
+    let filter = |q: VolumeQuery| VolumeFilter::from_query(&q);
+
+    let f = filter(VolumeQuery { gid: Some("2000".into()),
+                                 created_after: Some("2019-06-01T00:00:00+02:00".into()),
+                                 details: Some("true".into()),
+                                 offset: Some("2".into()),
+                                 limit: Some("2".into()),
+                                 ..Default::default() }).unwrap();
+    assert_eq!(f.gid, Some(2000));
+    assert!(f.tags.is_empty());
+    assert_eq!(f.created_after, Some(Utc.timestamp_opt(1_559_340_000, 0).unwrap()));
+    assert!(f.details);
+    assert_eq!(f.page(vec![1, 2, 3, 4, 5]), (vec![3, 4], Some(4)));
+    assert_eq!(f.page(vec![1, 2, 3, 4]), (vec![3, 4], None));
+    assert_eq!(f.page(vec![1]), (vec![], None));
+    assert_eq!(VolumeFilter::default().page(vec![1, 2]), (vec![1, 2], None));
+
+    for bad in vec![VolumeQuery { gid: Some("staff".into()), ..Default::default() },
+                    VolumeQuery { created_before: Some("yesterday".into()), ..Default::default() },
+                    VolumeQuery { details: Some("yes".into()), ..Default::default() },
+                    VolumeQuery { tag: Some("team:a,".into()), ..Default::default() },
+                    VolumeQuery { limit: Some("0".into()), ..Default::default() },
+                    VolumeQuery { offset: Some("-1".into()), ..Default::default() }]
+    {
+        assert_eq!(filter(bad).map_err(|e| e.code()), Err("invalid_input"));
+    }
+}
+
+#[test]
+fn test_parse_created() {
+    assert_eq!(parse_created("1559340000"), Some(Utc.timestamp_opt(1_559_340_000, 0).unwrap()));
+    assert_eq!(parse_created("1559340000\0"), Some(Utc.timestamp_opt(1_559_340_000, 0).unwrap()));
+    assert_eq!(parse_created("yesterday"), None);
+    assert_eq!(parse_created(&i64::MAX.to_string()), None);
+}
@@ -0,0 +1,62 @@
+#!/bin/bash
+# Create volumes concurrently against a running piragua and report latency.
+# Run it once with `native = false` (the baseline) and once with
+# `native = true` in the [quota] section, reloading piragua in between, to
+# compare the gluster cli with gfapi quotas.
+#
+#   TOKEN=<jwt> LABEL=native ./tests/bench_create.sh [url] [volumes] [concurrency]
+#
+# Besides the summary it prints the setup and a row for the results table in
+# the README's Benchmarks section.  The volumes are deleted again afterwards.
+set -euo pipefail
+
+URL=${1:-http://localhost:8080}
+COUNT=${2:-100}
+CONCURRENCY=${3:-10}
+: "${TOKEN:?set TOKEN to a token piragua accepts}"
+LABEL=${LABEL:-unlabelled}
+
+DIR=$(dirname "$0")
+OUT=$(mktemp)
+trap 'rm -f "$OUT"' EXIT
+
+create() {
+    curl -s -o /dev/null -D - -w 'time %{time_total}\n' \
+         -H "Authorization: Bearer $TOKEN" -H 'Content-Type: application/json' \
+         --data @"$DIR/create_volume" "$URL/volumes"
+}
+export -f create
+export URL TOKEN DIR
+
+start=$(date +%s.%N)
+seq "$COUNT" | xargs -P "$CONCURRENCY" -I{} bash -c create > "$OUT"
+end=$(date +%s.%N)
+
+# What the numbers were measured on
+version=$(curl -s "$URL/version" | sed -n 's/.*"version":"\([^"]*\)".*/\1/p')
+echo "piragua ${version:-unknown} at $URL, $(date -u +%Y-%m-%d)"
+if command -v gluster > /dev/null; then
+    gluster --version | head -1
+    gluster volume info | grep -E '^(Volume Name|Type|Number of Bricks):' || true
+fi
+
+failed=$(grep -c '^HTTP/1.1 [^2]' "$OUT" || true)
+grep '^time ' "$OUT" | cut -d' ' -f2 | sort -n | awk -v count="$COUNT" \
+    -v concurrency="$CONCURRENCY" -v failed="$failed" -v wall="$(echo "$end - $start" | bc)" \
+    -v label="$LABEL" '
+    { t[NR] = $1; sum += $1 }
+    END {
+        p50 = t[int(NR * 0.50) + 1]
+        p95 = t[int(NR * 0.95) + 1 > NR ? NR : int(NR * 0.95) + 1]
+        p99 = t[int(NR * 0.99) + 1 > NR ? NR : int(NR * 0.99) + 1]
+        printf "%d creates, %d at a time, %d failed, %.1fs total\n", count, concurrency, failed, wall
+        printf "mean %.3fs  p50 %.3fs  p95 %.3fs  p99 %.3fs  max %.3fs\n", sum / NR,
+               p50, p95, p99, t[NR]
+        printf "| %s | %d | %d | %d | %.3f | %.3f | %.3f | %.3f |\n", label, count, concurrency,
+               failed, sum / NR, p50, p95, p99
+    }'
+
+# Clean up everything we made
+grep -i '^location: ' "$OUT" | tr -d '\r' | cut -d' ' -f2 | while read -r location; do
+    curl -s -o /dev/null -X DELETE -H "Authorization: Bearer $TOKEN" "$URL$location"
+done